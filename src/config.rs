@@ -1,3 +1,8 @@
+// Version 2: percent-encoded paths, atime in file entries, mode and mtime in directory entries
+pub const PROTOCOL_VERSION: u32 = 2;
+// Protocol versions the client can talk
+pub const SUPPORTED_VERSIONS: &[u32] = &[2];
+
 pub const CHUNK_BITS: u32 = 20;
 pub const MAX_CHUNK_SIZE: usize = (1 << CHUNK_BITS) * 16;
 pub const BASE64_LINE_LENGTH: usize = 64;
@@ -6,3 +11,13 @@ pub const BASE64_LINE_LENGTH: usize = 64;
 //pub const BASE64_LINE_LENGTH: usize = 12;
 
 //pub const USE_MMAP: bool = true;
+
+// Clock difference between nodes (in seconds) that makes "newest wins" unreliable
+pub const MAX_CLOCK_SKEW: i64 = 5;
+
+// Seconds between the scanning throughput reports of a node still collecting
+pub const SCAN_REPORT_INTERVAL: u64 = 5;
+
+pub const IGNORE_FILE: &str = ".syncrignore";
+pub const NOSYNC_MARKER: &str = ".nosync";
+pub const TMP_SUFFIX: &str = ".SyNcR-TmP";
@@ -1,31 +1,61 @@
 use async_process;
-use async_std::{io as aio};
+use async_std::{io as aio, prelude::*, task};
 use std::error::Error;
 
+use crate::util;
+
 pub struct Connect {
     pub send: async_process::ChildStdin,
-    pub recv: async_std::io::BufReader<async_process::ChildStdout>
+    pub recv: async_std::io::BufReader<async_process::ChildStdout>,
+    pub child: async_process::Child,
+    // Forwards the stderr of the process
+    pub stderr: Option<task::JoinHandle<()>>
+}
+
+// Split a location into host (None for local directories) and directory
+pub fn split_host(location: &str) -> (Option<&str>, &str) {
+    if location.starts_with('/') || location.starts_with('.') || location.starts_with('~') {
+        return (None, location);
+    }
+    match location.find(':') {
+        Some(colon_pos) => (Some(&location[..colon_pos]), &location[colon_pos+1..]),
+        None => (None, location)
+    }
 }
 
-pub async fn connect(dir: &str) -> Result<Connect, Box<dyn Error>> {
-    let mut child: async_process::Child;
-    if let Some(colon_pos) = if &dir[..1] == "/" || &dir[..1] == "." || &dir[..1] == "~" {None} else {dir.find(':')} {
-        let host = &dir[..colon_pos];
-        let dir = &dir[colon_pos+1..];
+// Start serve for the directory. The stderr of the process is forwarded prefixed with the tag, so
+// messages of the nodes can be told apart. With debug the command line is printed too.
+pub async fn connect(dir: &str, serve_args: &[&str], tag: &str, debug: bool) -> Result<Connect, Box<dyn Error>> {
+    let mut args: Vec<String> = Vec::new();
+    if let (Some(host), dir) = split_host(dir) {
         println!("Connecting {} : {}", &host, &dir);
-        child = async_process::Command::new("ssh").arg(host).arg("syncr").arg("serve").arg(dir)
-            .stdin(async_process::Stdio::piped())
-            .stdout(async_process::Stdio::piped())
-            .spawn()
-            .expect("Failed to spawn subprocess");
+        args.extend(["ssh", host, "syncr", "serve"].iter().map(|arg| String::from(*arg)));
+        args.extend(serve_args.iter().map(|arg| util::shell_quote(arg)));
+        args.push(String::from(dir));
     } else {
-        child = async_process::Command::new("syncr").arg("serve").arg(dir)
-            .stdin(async_process::Stdio::piped())
-            .stdout(async_process::Stdio::piped())
-            .spawn()
-            .expect("Failed to spawn subprocess");
+        args.extend(["syncr", "serve"].iter().map(|arg| String::from(*arg)));
+        args.extend(serve_args.iter().map(|arg| String::from(*arg)));
+        args.push(String::from(dir));
+    }
+    if debug {
+        eprintln!("[{}] Running: {}", tag, args.join(" "));
     }
+    let mut child = async_process::Command::new(&args[0]).args(&args[1..])
+        .stdin(async_process::Stdio::piped())
+        .stdout(async_process::Stdio::piped())
+        .stderr(async_process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", args[0], e))?;
     let send = child.stdin.take().expect("Failed to spawn subprocess");
     let recv = aio::BufReader::new(child.stdout.take().expect("Failed to spawn subprocess"));
-    Ok(Connect { send, recv })
+    let stderr = child.stderr.take().map(|stderr| {
+        let tag = String::from(tag);
+        task::spawn(async move {
+            let mut lines = aio::BufReader::new(stderr).lines();
+            while let Some(Ok(line)) = lines.next().await {
+                eprintln!("[{}] {}", tag, line);
+            }
+        })
+    });
+    Ok(Connect { send, recv, child, stderr })
 }
@@ -0,0 +1,53 @@
+use std::{error, fmt};
+
+////////////
+// Errors //
+////////////
+const MAX_LINE_LENGTH: usize = 80;
+
+// Unexpected or malformed response from a node
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub node: String,
+    pub command: &'static str,
+    pub message: String,
+    pub line: String
+}
+
+impl ProtocolError {
+    pub fn new(node: &dyn fmt::Display, command: &'static str, message: &str, line: &str) -> ProtocolError {
+        ProtocolError {
+            node: node.to_string(),
+            command,
+            message: String::from(message),
+            line: line.trim().chars().take(MAX_LINE_LENGTH).collect()
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Node {}: {} failed: {}", self.node, self.command, self.message)?;
+        if !self.line.is_empty() {
+            write!(f, ": {:?}", self.line)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ProtocolError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_protocol_error() {
+        let e = ProtocolError::new(&"2 (host:dir)", "COMMIT", "Unexpected response", "WHAT?\n");
+        assert_eq!(e.to_string(), "Node 2 (host:dir): COMMIT failed: Unexpected response: \"WHAT?\"");
+        let e = ProtocolError::new(&1, "LIST", "Connection closed", "");
+        assert_eq!(e.to_string(), "Node 1: LIST failed: Connection closed");
+        // Long lines are cut at a character boundary
+        let e = ProtocolError::new(&1, "READ", "Invalid chunk", &format!("{}éé", "x".repeat(MAX_LINE_LENGTH - 1)));
+        assert_eq!(e.line, format!("{}é", "x".repeat(MAX_LINE_LENGTH - 1)));
+    }
+}
@@ -0,0 +1,281 @@
+use glob;
+use std::{env, fs, io, path};
+use std::error::Error;
+
+use crate::config;
+use crate::serve::ServeOptions;
+
+/////////////
+// Exclude //
+/////////////
+struct Rule {
+    pattern: glob::Pattern,
+    negate: bool,
+    // A leading "/" anchors the pattern to the root, otherwise it also matches the file name
+    anchored: bool,
+    // Where the rule comes from (ignore file and line, or command line option)
+    origin: String
+}
+
+pub struct Exclude {
+    builtin: Vec<glob::Pattern>,
+    rules: Vec<Rule>,
+    pub hidden: bool,
+    // Directories containing a file with this name are excluded with all their contents
+    pub marker: Option<String>
+}
+
+impl Exclude {
+    pub fn new() -> Result<Exclude, Box<dyn Error>> {
+        Ok(Exclude {
+            builtin: vec![glob::Pattern::new(&format!("**/*{}", glob::Pattern::escape(config::TMP_SUFFIX)))?],
+            rules: Vec::new(),
+            hidden: false,
+            marker: None
+        })
+    }
+
+    // Add a rule in .syncrignore syntax ("!" negates, "#" starts a comment)
+    pub fn add_rule(&mut self, line: &str, origin: &str) -> Result<(), glob::PatternError> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let (negate, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, line)
+        };
+        let anchored = pattern.starts_with('/');
+        let pattern = glob::Pattern::new(pattern.trim_start_matches('/'))?;
+        self.rules.push(Rule { pattern, negate, anchored, origin: String::from(origin) });
+        Ok(())
+    }
+
+    // Load rules from an ignore file, a missing file is not an error
+    pub fn load_ignore_file(&mut self, path: &path::Path) -> Result<(), Box<dyn Error>> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into())
+        };
+        for (idx, line) in content.lines().enumerate() {
+            self.add_rule(line, &format!("{}:{}", path.display(), idx + 1))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_marked(&self, dir: &path::Path) -> bool {
+        self.marker.as_ref().is_some_and(|marker| dir.join(marker).exists())
+    }
+
+    pub fn is_excluded(&self, path: &path::Path) -> bool {
+        self.explain(path).0
+    }
+
+    // Whether traversal skips the path: it is excluded, or is within an excluded or marked directory
+    pub fn is_skipped(&self, path: &path::Path) -> bool {
+        ancestors(path).any(|p| self.is_excluded(p) || self.is_marked(p))
+    }
+
+    // Whether the path is a marked directory or within one
+    pub fn in_marked(&self, path: &path::Path) -> bool {
+        ancestors(path).any(|p| self.is_marked(p))
+    }
+
+    // Whether the path is excluded, with the rule that decided it (None if no rule matched)
+    pub fn explain(&self, path: &path::Path) -> (bool, Option<String>) {
+        if let Some(p) = self.builtin.iter().find(|p| p.matches_path(path)) {
+            return (true, Some(format!("built-in: {}", p.as_str())));
+        }
+        let path = path.strip_prefix(".").unwrap_or(path);
+        let name = path.file_name().map(path::Path::new);
+
+        // Hidden files are excluded unless a later rule includes them again. Only the name counts,
+        // the contents of an included hidden directory are included too.
+        let mut decision = (false, None);
+        if self.hidden && name.is_some_and(|name| name.to_string_lossy().starts_with('.')) {
+            decision = (true, Some(String::from("--exclude-hidden")));
+        }
+
+        // The last matching rule wins, like in .gitignore
+        for rule in &self.rules {
+            if rule.pattern.matches_path(path) || (!rule.anchored && name.is_some_and(|n| rule.pattern.matches_path(n))) {
+                let negate = if rule.negate { "!" } else { "" };
+                let anchor = if rule.anchored { "/" } else { "" };
+                decision = (!rule.negate, Some(format!("{}: {}{}{}", rule.origin, negate, anchor, rule.pattern.as_str())));
+            }
+        }
+        decision
+    }
+}
+
+// The path and its parents below the synced directory
+fn ancestors(path: &path::Path) -> impl Iterator<Item=&path::Path> {
+    path.ancestors().take_while(|p| !p.as_os_str().is_empty() && *p != path::Path::new("."))
+}
+
+pub fn load(dir: &path::Path, opts: &ServeOptions) -> Result<Exclude, Box<dyn Error>> {
+    let mut exclude = Exclude::new()?;
+    if opts.no_builtin_excludes {
+        exclude.builtin.clear();
+    }
+    // The partial files are never synced themselves
+    if let Some(partial_dir) = opts.partial_dir.as_deref().filter(|dir| path::Path::new(dir).is_relative()) {
+        let partial_dir = partial_dir.trim_start_matches("./").trim_end_matches('/');
+        exclude.builtin.push(glob::Pattern::new(&format!("./{}", glob::Pattern::escape(partial_dir)))?);
+    }
+    exclude.hidden = opts.exclude_hidden;
+    exclude.marker = opts.nosync_marker.clone();
+    exclude.load_ignore_file(&dir.join(config::IGNORE_FILE))?;
+    for pattern in &opts.exclude {
+        exclude.add_rule(pattern, "--exclude")?;
+    }
+    for pattern in &opts.include {
+        exclude.add_rule(&format!("!{}", pattern), "--include")?;
+    }
+    Ok(exclude)
+}
+
+// Print why each path (relative to the directory) is excluded or included
+pub fn explain(dir: &str, paths: &[String], opts: &ServeOptions) -> Result<(), Box<dyn Error>> {
+    env::set_current_dir(dir)?;
+    let root = path::Path::new(".");
+    let exclude = load(root, opts)?;
+    for path in paths {
+        let path = path::Path::new(path);
+        // Traversal stops at the first excluded or marked directory above the path
+        let mut parents: Vec<&path::Path> = path.ancestors().skip(1).take_while(|p| *p != root).collect();
+        parents.reverse();
+        let skipped = parents.iter().find_map(|parent| match exclude.explain(parent) {
+            (true, reason) => Some(format!("{} is excluded by {}", parent.display(), reason.unwrap_or_default())),
+            _ if exclude.is_marked(parent) => Some(format!("{} has a {} file", parent.display(), exclude.marker.as_deref().unwrap_or_default())),
+            _ => None
+        });
+        match (skipped, exclude.explain(path)) {
+            (Some(why), _) => println!("{}: excluded, {}", path.display(), why),
+            (None, (true, Some(reason))) => println!("{}: excluded by {}", path.display(), reason),
+            (None, (false, Some(reason))) => println!("{}: included by {}", path.display(), reason),
+            (None, _) => println!("{}: included (no rule matches)", path.display())
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_ignore_rules() {
+        let mut exclude = Exclude::new().unwrap();
+        exclude.add_rule("# comment", "test").unwrap();
+        exclude.add_rule("*.log", "test").unwrap();
+        exclude.add_rule("!keep.log", "test").unwrap();
+        exclude.add_rule("build", "test").unwrap();
+        assert!(exclude.is_excluded(path::Path::new("./a.log")));
+        assert!(exclude.is_excluded(path::Path::new("./sub/b.log")));
+        assert!(!exclude.is_excluded(path::Path::new("./sub/keep.log")));
+        assert!(exclude.is_excluded(path::Path::new("./sub/build")));
+        assert!(!exclude.is_excluded(path::Path::new("./src/main.rs")));
+        assert!(exclude.is_excluded(path::Path::new(&format!("./x{}", config::TMP_SUFFIX))));
+    }
+
+    #[test]
+    fn test_anchored_rules() {
+        let mut exclude = Exclude::new().unwrap();
+        exclude.add_rule("/build", "test").unwrap();
+        exclude.add_rule("/doc/*.html", "test").unwrap();
+        assert!(exclude.is_excluded(path::Path::new("./build")));
+        assert!(!exclude.is_excluded(path::Path::new("./sub/build")));
+        assert!(exclude.is_excluded(path::Path::new("./doc/index.html")));
+        assert!(!exclude.is_excluded(path::Path::new("./sub/doc/index.html")));
+        assert_eq!(exclude.explain(path::Path::new("./build")).1, Some(String::from("test: /build")));
+    }
+
+    #[test]
+    fn test_ignore_file() {
+        let dir = env::temp_dir().join(format!("syncr-test-ignore-{}", std::process::id()));
+        fs::create_dir_all(dir.join("logs")).unwrap();
+        for file in &["a.log", "logs/b.log", "logs/keep.log", "data.txt"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        fs::write(dir.join(config::IGNORE_FILE), "# logs\n*.log\n!keep.log\n").unwrap();
+        let exclude = load(&dir, &ServeOptions::default());
+        fs::remove_dir_all(&dir).unwrap();
+        let exclude = exclude.unwrap();
+        assert!(exclude.is_excluded(path::Path::new("./a.log")));
+        assert!(exclude.is_excluded(path::Path::new("./logs/b.log")));
+        assert!(!exclude.is_excluded(path::Path::new("./logs/keep.log")));
+        assert!(!exclude.is_excluded(path::Path::new("./data.txt")));
+        assert_eq!(exclude.explain(path::Path::new("./logs/keep.log")).1,
+            Some(format!("{}:3: !keep.log", dir.join(config::IGNORE_FILE).display())));
+    }
+
+    #[test]
+    fn test_explain() {
+        let opts = ServeOptions {
+            exclude_hidden: true,
+            exclude: vec![String::from("*.log"), String::from("build")],
+            include: vec![String::from("keep.log")],
+            ..Default::default()
+        };
+        let exclude = load(path::Path::new("/nonexistent"), &opts).unwrap();
+        let explain = |path: &str| exclude.explain(path::Path::new(path));
+        assert_eq!(explain("./a.log"), (true, Some(String::from("--exclude: *.log"))));
+        assert_eq!(explain("./sub/keep.log"), (false, Some(String::from("--include: !keep.log"))));
+        assert_eq!(explain("./build"), (true, Some(String::from("--exclude: build"))));
+        assert_eq!(explain("./.config"), (true, Some(String::from("--exclude-hidden"))));
+        let temp = format!("./x{}", config::TMP_SUFFIX);
+        assert_eq!(explain(&temp).1, Some(format!("built-in: **/*{}", config::TMP_SUFFIX)));
+        assert_eq!(explain("./src/main.rs"), (false, None));
+    }
+
+    #[test]
+    fn test_is_marked() {
+        // Relies on the tests running in the package root
+        let mut exclude = Exclude::new().unwrap();
+        assert!(!exclude.is_marked(path::Path::new("./src")));
+        exclude.marker = Some(String::from("main.rs"));
+        assert!(exclude.is_marked(path::Path::new("./src")));
+        assert!(!exclude.is_marked(path::Path::new(".")));
+    }
+
+    #[test]
+    fn test_no_builtin_excludes() {
+        let temp = format!("./sub/x{}", config::TMP_SUFFIX);
+        let opts = ServeOptions { exclude: vec![String::from("*.log")], ..Default::default() };
+        let exclude = load(path::Path::new("/nonexistent"), &opts).unwrap();
+        assert!(exclude.is_excluded(path::Path::new(&temp)));
+        let opts = ServeOptions { no_builtin_excludes: true, ..opts };
+        let exclude = load(path::Path::new("/nonexistent"), &opts).unwrap();
+        assert!(!exclude.is_excluded(path::Path::new(&temp)));
+        assert!(exclude.is_excluded(path::Path::new("./a.log")));
+        let opts = ServeOptions { partial_dir: Some(String::from("./.partial/")), ..opts };
+        let exclude = load(path::Path::new("/nonexistent"), &opts).unwrap();
+        assert!(exclude.is_excluded(path::Path::new("./.partial")));
+        assert!(!exclude.is_excluded(path::Path::new("./sub/.partial")));
+    }
+
+    #[test]
+    fn test_exclude_hidden() {
+        let mut exclude = Exclude::new().unwrap();
+        exclude.hidden = true;
+        exclude.add_rule("!.keep", "test").unwrap();
+        assert!(!exclude.is_excluded(path::Path::new(".")));
+        assert!(exclude.is_excluded(path::Path::new("./.bashrc")));
+        assert!(exclude.is_excluded(path::Path::new("./.config")));
+        assert!(exclude.is_skipped(path::Path::new("./.config/app.conf")));
+        assert!(!exclude.is_excluded(path::Path::new("./sub/.keep")));
+        assert!(!exclude.is_excluded(path::Path::new("./sub/file")));
+    }
+
+    #[test]
+    fn test_include_hidden_dir() {
+        let opts = ServeOptions { exclude_hidden: true, include: vec![String::from(".config")], ..Default::default() };
+        let exclude = load(path::Path::new("/nonexistent"), &opts).unwrap();
+        assert!(!exclude.is_skipped(path::Path::new("./.config")));
+        assert!(!exclude.is_skipped(path::Path::new("./.config/app.conf")));
+        assert!(exclude.is_skipped(path::Path::new("./.config/.cache")));
+        assert!(exclude.is_skipped(path::Path::new("./.local/share/file")));
+        assert!(!exclude.is_skipped(path::Path::new("./sub/file")));
+    }
+}
@@ -1,10 +1,13 @@
 use async_std::{task};
-use clap::{Arg, App, AppSettings, SubCommand};
-use std::{env, path};
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
+use std::{env, fs, path};
+use std::collections::BTreeMap;
 use std::error::Error;
 
 mod config;
 mod connect;
+mod error;
+mod exclude;
 mod serve;
 mod sync;
 mod types;
@@ -13,6 +16,72 @@ mod util;
 ///////////////////////
 // Utility functions //
 ///////////////////////
+fn values(matches: &ArgMatches, name: &str) -> Vec<String> {
+    matches.values_of(name).into_iter().flatten().map(String::from).collect()
+}
+
+// Paths are relative to the synced directories, like "./sub/file" in the listings
+fn relative_path(path: &str) -> String {
+    format!("./{}", path.trim().trim_start_matches("./").trim_start_matches('/'))
+}
+
+// Read a list of relative paths, one per line, empty lines and "#" comments are skipped
+fn read_file_list(file: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let content = fs::read_to_string(file)?;
+    Ok(content.lines()
+        .map(|line| line.trim().trim_start_matches("./").trim_start_matches('/'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(relative_path)
+        .collect())
+}
+
+fn nosync_marker(matches: &ArgMatches) -> Option<String> {
+    match matches.value_of("nosync-marker") {
+        Some(marker) => Some(String::from(marker)),
+        None if matches.is_present("nosync") => Some(String::from(config::NOSYNC_MARKER)),
+        None => None
+    }
+}
+
+// The options selecting what is synced, shared by the subcommands that traverse directories
+fn exclude_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("exclude-hidden")
+            .long("exclude-hidden").help("Exclude hidden files and directories"),
+        Arg::with_name("exclude")
+            .long("exclude").takes_value(true).multiple(true).number_of_values(1).help("Exclude files matching the pattern"),
+        Arg::with_name("include")
+            .long("include").takes_value(true).multiple(true).number_of_values(1).help("Include files matching the pattern, even if excluded"),
+        Arg::with_name("no-builtin-excludes")
+            .long("no-builtin-excludes").help("Don't exclude the temp files left behind by interrupted syncs"),
+        Arg::with_name("nosync")
+            .long("nosync").help("Exclude directories containing a .nosync file"),
+        Arg::with_name("nosync-marker")
+            .long("nosync-marker").takes_value(true).help("Exclude directories containing a file with this name")
+    ]
+}
+
+fn parse_labels<'a>(args: impl Iterator<Item=&'a str>) -> Result<BTreeMap<usize, String>, Box<dyn Error>> {
+    let mut labels = BTreeMap::new();
+    for arg in args {
+        match arg.find('=') {
+            Some(pos) => { labels.insert(arg[..pos].parse()?, String::from(&arg[pos+1..])); },
+            None => return Err(format!("Invalid label (expected <node number>=<name>): {}", arg).into())
+        }
+    }
+    Ok(labels)
+}
+
+fn parse_phase_timeouts<'a>(args: impl Iterator<Item=&'a str>) -> Result<BTreeMap<sync::Phase, u64>, Box<dyn Error>> {
+    let mut timeouts = BTreeMap::new();
+    for arg in args {
+        match arg.find('=') {
+            Some(pos) => { timeouts.insert(arg[..pos].parse()?, arg[pos+1..].parse()?); },
+            None => return Err(format!("Invalid phase timeout (expected <phase>=<seconds>): {}", arg).into())
+        }
+    }
+    Ok(timeouts)
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = App::new("SyncR").version("0.1.0").author("Szilard Hajba <szilard@symbion.hu>")
@@ -22,32 +91,206 @@ fn main() -> Result<(), Box<dyn Error>> {
             .short("p").long("profile").takes_value(true).help("Profile"))
         .subcommand(SubCommand::with_name("serve")
             .about("Serving mode (used internally)")
+            .args(&exclude_args())
+            .arg(Arg::with_name("mkdirs")
+                .long("mkdirs").help("Create the directory if it doesn't exist"))
+            .arg(Arg::with_name("partial-dir")
+                .long("partial-dir").takes_value(true).help("Keep the temp files of interrupted syncs in this directory and reuse their chunks"))
+            .arg(Arg::with_name("read-only")
+                .long("read-only").help("Refuse all writes"))
+            .arg(Arg::with_name("skip-failed")
+                .long("skip-failed").help("Commit the complete files even if some files couldn't be completed"))
+            .arg(Arg::with_name("continue-on-error")
+                .long("continue-on-error").help("Report and skip files that can't be written instead of stopping"))
+            .arg(Arg::with_name("inplace")
+                .long("inplace").help("Update existing files in place instead of replacing them"))
+            .arg(Arg::with_name("times")
+                .long("times").help("Preserve modification times"))
+            .arg(Arg::with_name("atimes")
+                .long("atimes").help("Preserve access times (and modification times)"))
             .arg(Arg::with_name("dir").required(true))
         )
         .subcommand(SubCommand::with_name("dump")
             .about("Dump directory data")
             .arg(Arg::with_name("dir").required(true))
         )
+        .subcommand(SubCommand::with_name("explain")
+            .about("Show which rule excludes or includes the given paths")
+            .args(&exclude_args())
+            .arg(Arg::with_name("dir").required(true))
+            .arg(Arg::with_name("path").required(true).multiple(true).help("Paths relative to the directory"))
+        )
+        .subcommand(SubCommand::with_name("recover")
+            .about("Find temp files left behind by an interrupted sync")
+            .arg(Arg::with_name("clean")
+                .long("clean").help("Remove the temp files"))
+            .arg(Arg::with_name("dir").required(true))
+        )
         .subcommand(SubCommand::with_name("sync")
             .about("Sync directories")
+            .args(&exclude_args())
+            .arg(Arg::with_name("dry-run")
+                .short("n").long("dry-run").help("Show what would be transferred without doing it"))
+            .arg(Arg::with_name("continue-on-error")
+                .long("continue-on-error").help("Report and skip files that can't be written instead of stopping the sync"))
+            .arg(Arg::with_name("debug-connect")
+                .long("debug-connect").help("Show the commands run for each node, their stderr and how they exited on failure"))
+            .arg(Arg::with_name("delay-updates")
+                .long("delay-updates").help("Wait until all nodes staged their changes before committing"))
+            .arg(Arg::with_name("files-from")
+                .long("files-from").takes_value(true).help("Sync only the paths listed in this file"))
+            .arg(Arg::with_name("human-readable")
+                .long("human-readable").help("Print sizes with adaptive units (KB, MB, ...)"))
+            .arg(Arg::with_name("binary-units")
+                .long("binary-units").help("Print sizes with binary units (KiB, MiB, ...)"))
+            .arg(Arg::with_name("label")
+                .short("l").long("label").takes_value(true).multiple(true).number_of_values(1)
+                .help("Name a node in the output (<node number>=<name>)"))
+            .arg(Arg::with_name("inplace")
+                .long("inplace").help("Update existing files in place when possible (keeps their inode, but the update is not atomic)"))
+            .arg(Arg::with_name("keepalive")
+                .long("keepalive").takes_value(true).help("Ping idle nodes every SECS seconds while others are still collecting"))
+            .arg(Arg::with_name("list-only")
+                .long("list-only").help("List files on all nodes without syncing"))
+            .arg(Arg::with_name("max-connections")
+                .long("max-connections").takes_value(true).help("Maximum number of connections established at once"))
+            .arg(Arg::with_name("min-free")
+                .long("min-free").takes_value(true).help("Free space (in bytes) to leave on the nodes after writing"))
+            .arg(Arg::with_name("mkdirs")
+                .long("mkdirs").help("Create missing directories"))
+            .arg(Arg::with_name("no-data")
+                .long("no-data").help("Only create directories and update metadata, don't transfer file contents"))
+            .arg(Arg::with_name("no-times")
+                .long("no-times").conflicts_with_all(&["times", "atimes"])
+                .help("Ignore modification times when comparing files (contents still go from the newest)"))
+            .arg(Arg::with_name("force-protocol-version")
+                .long("force-protocol-version").takes_value(true).hidden(true)
+                .help("Require all nodes to talk this protocol version (for debugging)"))
+            .arg(Arg::with_name("prune-empty-dirs")
+                .short("m").long("prune-empty-dirs").help("Don't create directories that contain no files"))
+            .arg(Arg::with_name("read-only")
+                .long("read-only").takes_value(true).multiple(true).number_of_values(1)
+                .help("Only use a node as a source, never write to it (<node number>)"))
+            .arg(Arg::with_name("relative")
+                .short("R").long("relative").help("Keep the full path of locations without a trailing slash on the other nodes (like rsync -R)"))
+            .arg(Arg::with_name("skip-failed")
+                .long("skip-failed").help("Skip files that couldn't be completed instead of aborting the commit"))
+            .arg(Arg::with_name("strict-clock")
+                .long("strict-clock").help("Refuse to sync if a node's clock differs too much from the local one"))
+            .arg(Arg::with_name("subpath")
+                .long("subpath").takes_value(true).multiple(true).number_of_values(1)
+                .help("Only sync this part of the directories (relative path, the whole tree is still scanned)"))
+            .arg(Arg::with_name("timeout")
+                .long("timeout").takes_value(true).help("Give up (without committing anything) if the sync takes longer than SECS seconds"))
+            .arg(Arg::with_name("trace-chunks")
+                .long("trace-chunks").help("Print each transferred chunk with its source and destination nodes"))
+            .arg(Arg::with_name("trailing-slash")
+                .long("trailing-slash").help("Sync locations without a trailing slash as a directory, not its contents (like rsync)"))
+            .arg(Arg::with_name("times")
+                .short("t").long("times").help("Preserve modification times"))
+            .arg(Arg::with_name("atimes")
+                .short("U").long("atimes").help("Preserve access times (and modification times)"))
+            .arg(Arg::with_name("verify-after")
+                .long("verify-after").help("Re-read and check the written files after commit"))
+            .arg(Arg::with_name("partial-dir")
+                .long("partial-dir").takes_value(true)
+                .help("Keep partially written files of interrupted syncs in this directory (relative to the synced ones) to reuse their data next time"))
+            .arg(Arg::with_name("phase-timeout")
+                .long("phase-timeout").takes_value(true).multiple(true).number_of_values(1)
+                .help("Give up (without committing anything) if a phase takes too long (<connect|collect|transfer>=<seconds>)"))
+            .arg(Arg::with_name("post-sync")
+                .long("post-sync").takes_value(true).help("Shell command to run after a successful sync"))
             .arg(Arg::with_name("dir").required(true).multiple(true))
         )
         .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("serve") {
         let dir = matches.value_of("dir").expect("ERROR");
-        return serve::serve(&dir)
+        let opts = serve::ServeOptions {
+            mkdirs: matches.is_present("mkdirs"),
+            times: matches.is_present("times"),
+            atimes: matches.is_present("atimes"),
+            exclude_hidden: matches.is_present("exclude-hidden"),
+            exclude: values(matches, "exclude"),
+            include: values(matches, "include"),
+            no_builtin_excludes: matches.is_present("no-builtin-excludes"),
+            nosync_marker: nosync_marker(matches),
+            partial_dir: matches.value_of("partial-dir").map(String::from),
+            read_only: matches.is_present("read-only"),
+            skip_failed: matches.is_present("skip-failed"),
+            continue_on_error: matches.is_present("continue-on-error"),
+            inplace: matches.is_present("inplace")
+        };
+        return serve::serve(&dir, &opts)
     } else if let Some(matches) = matches.subcommand_matches("dump") {
         let dir = matches.value_of("dir").expect("ERROR");
         env::set_current_dir(&dir)?;
-        let dump_state = serve::serve_list(path::PathBuf::from("."))?;
+        let dump_state = serve::serve_list(path::PathBuf::from("."), &serve::ServeOptions::default())?;
 
         for (h, p) in &dump_state.chunks {
             println!("{}: {:?}", h, p);
         }
+    } else if let Some(matches) = matches.subcommand_matches("explain") {
+        let dir = matches.value_of("dir").expect("ERROR");
+        let paths: Vec<String> = matches.values_of("path").expect("ERROR").map(relative_path).collect();
+        let opts = serve::ServeOptions {
+            exclude_hidden: matches.is_present("exclude-hidden"),
+            exclude: values(matches, "exclude"),
+            include: values(matches, "include"),
+            no_builtin_excludes: matches.is_present("no-builtin-excludes"),
+            nosync_marker: nosync_marker(matches),
+            ..Default::default()
+        };
+        return exclude::explain(dir, &paths, &opts);
+    } else if let Some(matches) = matches.subcommand_matches("recover") {
+        let dir = matches.value_of("dir").expect("ERROR");
+        return serve::recover(dir, matches.is_present("clean"));
     } else if let Some(matches) = matches.subcommand_matches("sync") {
         let dirs: Vec<&str> = matches.values_of("dir").expect("ERROR").collect();
-        return task::block_on(sync::sync(dirs));
+        let opts = sync::SyncOptions {
+            dry_run: matches.is_present("dry-run"),
+            continue_on_error: matches.is_present("continue-on-error"),
+            debug_connect: matches.is_present("debug-connect"),
+            delay_updates: matches.is_present("delay-updates"),
+            exclude_hidden: matches.is_present("exclude-hidden"),
+            exclude: values(matches, "exclude"),
+            include: values(matches, "include"),
+            files_from: matches.value_of("files-from").map(read_file_list).transpose()?,
+            human_readable: matches.is_present("human-readable") || matches.is_present("binary-units"),
+            binary_units: matches.is_present("binary-units"),
+            list_only: matches.is_present("list-only"),
+            labels: parse_labels(matches.values_of("label").into_iter().flatten())?,
+            inplace: matches.is_present("inplace"),
+            keepalive: matches.value_of("keepalive").map(|n| n.parse()).transpose()?.unwrap_or(0),
+            max_connections: matches.value_of("max-connections").map(|n| n.parse()).transpose()?.unwrap_or(0),
+            min_free: matches.value_of("min-free").map(|n| n.parse()).transpose()?.unwrap_or(0),
+            mkdirs: matches.is_present("mkdirs"),
+            no_builtin_excludes: matches.is_present("no-builtin-excludes"),
+            no_data: matches.is_present("no-data"),
+            nosync_marker: nosync_marker(matches),
+            no_times: matches.is_present("no-times"),
+            partial_dir: matches.value_of("partial-dir").map(String::from),
+            phase_timeouts: parse_phase_timeouts(matches.values_of("phase-timeout").into_iter().flatten())?,
+            post_sync: matches.value_of("post-sync").map(String::from),
+            protocol_version: match matches.value_of("force-protocol-version").map(|n| n.parse()).transpose()? {
+                Some(version) if !config::SUPPORTED_VERSIONS.contains(&version) =>
+                    return Err(format!("Unsupported protocol version: {}", version).into()),
+                version => version
+            },
+            prune_empty_dirs: matches.is_present("prune-empty-dirs"),
+            read_only: matches.values_of("read-only").into_iter().flatten().map(|n| n.parse()).collect::<Result<_, _>>()?,
+            relative: matches.is_present("relative"),
+            times: matches.is_present("times"),
+            atimes: matches.is_present("atimes"),
+            strict_clock: matches.is_present("strict-clock"),
+            subpaths: matches.values_of("subpath").into_iter().flatten().map(relative_path).collect(),
+            timeout: matches.value_of("timeout").map(|n| n.parse()).transpose()?.unwrap_or(0),
+            trace_chunks: matches.is_present("trace-chunks"),
+            skip_failed: matches.is_present("skip-failed"),
+            trailing_slash: matches.is_present("trailing-slash"),
+            verify_after: matches.is_present("verify-after")
+        };
+        return task::block_on(sync::sync(dirs, &opts));
     }
 
     Ok(())
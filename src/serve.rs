@@ -1,18 +1,17 @@
 use async_std::{prelude::*, task, fs as afs};
 use base64;
-use glob;
 use rollsum::Bup;
 use std::cell::RefCell;
-use std::collections::{BTreeMap};
-use std::{env, fs, path, io, pin::Pin};
+use std::collections::{BTreeMap, BTreeSet};
+use std::{cmp, env, ffi, fs, mem, path, io, pin::Pin, time};
 use std::error::Error;
 use std::io::{Write};
-use std::os::unix::{fs::MetadataExt, prelude::PermissionsExt};
-//use std::{thread, time};
+use std::os::unix::{ffi::OsStrExt, fs::MetadataExt, prelude::PermissionsExt};
 
 use crate::config;
+use crate::exclude;
 use crate::util;
-use crate::types::{FileChunk, HashChunk, FileData};
+use crate::types::{FileChunk, HashChunk, FileData, DirData};
 
 ///////////
 // Utils //
@@ -20,19 +19,49 @@ use crate::types::{FileChunk, HashChunk, FileData};
 fn tmp_filename(path: &path::Path) -> path::PathBuf {
     let mut filepath = path::PathBuf::from(path);
     let mut filename = path.file_name().expect("Protocol error!").to_os_string();
-    filename.push(".SyNcR-TmP");
+    filename.push(config::TMP_SUFFIX);
     filepath.set_file_name(filename);
     filepath
 }
 
+// Run a filesystem operation on path. If it fails with permission denied because the
+// parent directory is read-only, retry with the directory temporarily made writable.
+fn with_writable_parent<T>(path: &path::Path, op: impl Fn() -> io::Result<T>) -> Result<T, Box<dyn Error>> {
+    match op() {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            let parent = match path.parent() {
+                Some(parent) if parent != path::Path::new("") => parent,
+                _ => path::Path::new(".")
+            };
+            let perms = fs::metadata(parent)?.permissions();
+            if perms.mode() & 0o200 != 0 {
+                return Err(format!("{}: {}", path.display(), e).into());
+            }
+            if let Err(e) = fs::set_permissions(parent, fs::Permissions::from_mode(perms.mode() | 0o200)) {
+                return Err(format!("{}: Cannot make directory {} writable: {}", path.display(), parent.display(), e).into());
+            }
+            let res = op();
+            fs::set_permissions(parent, perms)?;
+            res.map_err(|e| format!("{}: {}", path.display(), e).into())
+        },
+        res => Ok(res?)
+    }
+}
+
 //////////
 // List //
 //////////
 pub struct DumpState {
-    pub exclude: Vec<glob::Pattern>,
+    pub exclude: exclude::Exclude,
     pub chunks: BTreeMap<String, Vec<Box<FileChunk>>>,
     pub missing: RefCell<BTreeMap<String, Vec<Box<FileChunk>>>>,
-    pub rename: RefCell<BTreeMap<path::PathBuf, path::PathBuf>>
+    pub failed: RefCell<BTreeSet<path::PathBuf>>,
+    // Files the local excludes and markers keep from being written, reported at commit
+    pub protected: RefCell<BTreeSet<path::PathBuf>>,
+    pub rename: RefCell<BTreeMap<path::PathBuf, Box<FileData>>>,
+    pub meta: RefCell<Vec<Box<FileData>>>,
+    // Directories to set the metadata of at commit (and whether they were created)
+    pub dirs: RefCell<Vec<(Box<DirData>, bool)>>
 }
 
 impl DumpState {
@@ -43,23 +72,30 @@ impl DumpState {
         }
     }
 
+    // Read a chunk from any of the files it was listed in, None if the chunk is unknown or
+    // the files vanished or changed since listing
     async fn read_chunk(&self, dir: &path::Path, hash: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        let fc_vec_opt = self.chunks.get(hash);
-
-        match fc_vec_opt {
-            Some(fc_vec) => {
-                let fc = &fc_vec[0];
-                let path = dir.join(&fc.path);
+        let fc_vec = match self.chunks.get(hash) {
+            Some(fc_vec) => fc_vec,
+            None => return Ok(None)
+        };
+        for fc in fc_vec {
+            let path = dir.join(&fc.path);
+            let mut buf: Vec<u8> = vec![0; fc.size];
+            let res: io::Result<()> = async {
                 let mut f = afs::File::open(&path).await?;
-                let mut buf: Vec<u8> = vec![0; fc.size];
                 f.seek(io::SeekFrom::Start(fc.offset)).await?;
-                f.read(&mut buf).await?;
-                // FIXME: Hash check, error handling
-                //eprintln!("Hash check: {} ?= {}", util::hash(&buf), hash);
-                Ok(Some(buf))
-            },
-            None => Ok(None)
+                f.read_exact(&mut buf).await
+            }.await;
+            match res {
+                Ok(()) if util::hash(&buf) == hash => return Ok(Some(buf)),
+                Ok(()) => eprintln!("File changed since listing: {}", fc.path.display()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::UnexpectedEof =>
+                    eprintln!("File vanished since listing: {}", fc.path.display()),
+                Err(e) => return Err(e.into())
+            }
         }
+        Ok(None)
     }
 
     async fn write_chunk(&self, path: &path::Path, chunk: &HashChunk, buf: &Vec<u8>) -> Result<(), Box<dyn Error>> {
@@ -70,58 +106,82 @@ impl DumpState {
     }
 }
 
+// Split the file into content defined chunks, calling on_chunk with offset, size and hash of each
+async fn chunk_file(path: &path::Path, mut on_chunk: impl FnMut(u64, usize, String)) -> Result<(), Box<dyn Error>> {
+    let mut f = afs::File::open(&path).await?;
+    let mut buf: Vec<u8> = vec![0; config::MAX_CHUNK_SIZE];
+
+    let mut n = f.read(&mut buf).await?;
+
+    let mut offset: u64 = 0;
+    //let mut bup = Bup::new_with_chunk_bits(config::CHUNK_BITS);
+    while n > 0 {
+        let mut bup = Bup::new_with_chunk_bits(config::CHUNK_BITS);
+        let mut endofs = config::MAX_CHUNK_SIZE;
+        if endofs > n {
+            endofs = n
+        }
+        if let Some(count) = bup.find_chunk_edge(&buf[..endofs]) {
+            on_chunk(offset, count, util::hash(&buf[..count]));
+            unsafe {
+                std::ptr::copy(buf[count..].as_mut_ptr(), buf.as_mut_ptr(), n - count);
+            }
+            offset += count as u64;
+            n -= count;
+        } else {
+            let count = endofs;
+            on_chunk(offset, count, util::hash(&buf[..count]));
+            offset += count as u64;
+            n -= count;
+        }
+        n += f.read(&mut buf[n..]).await?;
+
+    }
+    Ok(())
+}
+
+async fn list_file(state: &mut DumpState, path: &path::Path, meta: &fs::Metadata) -> Result<(), Box<dyn Error>> {
+    println!("F:{}:{}:{}:{}:{}:{}:{}", util::encode_path(path), meta.mode(), meta.uid(), meta.gid(), meta.size(), meta.mtime(), meta.atime());
+
+    chunk_file(path, |offset, count, h| {
+        println!("C:{}:{}:{}", offset, count, &h);
+        state.add_chunk(h, path.to_path_buf(), offset, count);
+    }).await
+}
+
+// Offer the chunks of the files kept by interrupted syncs (--partial-dir) for local reuse
+async fn list_partials(state: &mut DumpState, opts: &ServeOptions) -> Result<(), Box<dyn Error>> {
+    let partial_dir = match &opts.partial_dir {
+        Some(dir) if path::Path::new(dir).is_dir() => path::Path::new(dir),
+        _ => return Ok(())
+    };
+    let mut found = Vec::new();
+    find_files(partial_dir, &mut found, &|_| true)?;
+    for path in found {
+        chunk_file(&path, |offset, count, h| {
+            println!("PC:{}:{}:{}", offset, count, &h);
+            state.add_chunk(h, path.clone(), offset, count);
+        }).await?;
+    }
+    Ok(())
+}
+
 fn traverse_dir<'a>(mut state: &'a mut DumpState, dir: path::PathBuf) -> Pin<Box<dyn Future<Output=Result<(), Box<dyn Error>>> + 'a>> {
 Box::pin(async move {
 	for entry in fs::read_dir(&dir)? {
 		let entry = entry?;
 		let path = entry.path();
-        if state.exclude[0].matches_path(&path) {
+        if state.exclude.is_excluded(&path) {
             continue;
         }
 
 		let meta = fs::metadata(&path)?;
 
 		if meta.is_file() {
-            println!("F:{}:{}:{}:{}:{}:{}", &path.to_str().unwrap(), meta.mode(), meta.uid(), meta.gid(), meta.size(), meta.mtime());
-
-            let mut f = afs::File::open(&path).await?;
-            let mut buf: Vec<u8> = vec![0; config::MAX_CHUNK_SIZE];
-
-            let mut n = f.read(&mut buf).await?;
-
-            let mut offset: u64 = 0;
-            //let mut bup = Bup::new_with_chunk_bits(config::CHUNK_BITS);
-            while n > 0 {
-                let mut bup = Bup::new_with_chunk_bits(config::CHUNK_BITS);
-                let mut endofs = config::MAX_CHUNK_SIZE;
-                if endofs > n {
-                    endofs = n
-                }
-                if let Some(count) = bup.find_chunk_edge(&buf[..endofs]) {
-                    let h = util::hash(&buf[..count]);
-                    println!("C:{}:{}:{}", offset, count, &h);
-                    //state.chunks.insert(h, path.clone());
-                    state.add_chunk(h, path.clone(), offset, count);
-                    unsafe {
-                        std::ptr::copy(buf[count..].as_mut_ptr(), buf.as_mut_ptr(), n - count);
-                    }
-                    offset += count as u64;
-                    n -= count;
-                } else {
-                    let count = endofs;
-                    let h = util::hash(&buf[..count]);
-                    println!("C:{}:{}:{}", offset, count, &h);
-                    //state.chunks.insert(h, path.clone());
-                    state.add_chunk(h, path.clone(), offset, count);
-                    offset += count as u64;
-                    n -= count;
-                }
-                n += f.read(&mut buf[n..]).await?;
-
-            }
+            list_file(state, &path, &meta).await?;
 		}
-        if meta.is_dir() {
-            println!("D:{}:{}:{}", path.to_str().unwrap(), meta.uid(), meta.gid());
+        if meta.is_dir() && !state.exclude.is_marked(&path) {
+            println!("D:{}:{}:{}:{}:{}", util::encode_path(&path), meta.mode(), meta.uid(), meta.gid(), meta.mtime());
             traverse_dir(&mut state, path).await?
         }
 	}
@@ -129,14 +189,58 @@ Box::pin(async move {
 })
 }
 
-pub fn serve_list(dir: path::PathBuf) -> Result<DumpState, Box<dyn Error>> {
-    let mut state = DumpState {
-        exclude: vec![glob::Pattern::new("**/*.SyNcR-TmP")?],
+fn new_dump_state(dir: &path::Path, opts: &ServeOptions) -> Result<DumpState, Box<dyn Error>> {
+    Ok(DumpState {
+        exclude: exclude::load(dir, opts)?,
         chunks: BTreeMap::new(),
         missing: RefCell::new(BTreeMap::new()),
-        rename: RefCell::new(BTreeMap::new())
-    };
+        failed: RefCell::new(BTreeSet::new()),
+        protected: RefCell::new(BTreeSet::new()),
+        rename: RefCell::new(BTreeMap::new()),
+        meta: RefCell::new(Vec::new()),
+        dirs: RefCell::new(Vec::new())
+    })
+}
+
+pub fn serve_list(dir: path::PathBuf, opts: &ServeOptions) -> Result<DumpState, Box<dyn Error>> {
+    let mut state = new_dump_state(&dir, opts)?;
     task::block_on(traverse_dir(&mut state, dir))?;
+    task::block_on(list_partials(&mut state, opts))?;
+
+    println!(".");
+    Ok(state)
+}
+
+// List only the given paths (read from the input until "."), directories are traversed
+async fn serve_list_files(dir: path::PathBuf, opts: &ServeOptions) -> Result<DumpState, Box<dyn Error>> {
+    let mut state = new_dump_state(&dir, opts)?;
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if io::stdin().read_line(&mut buf)? == 0 {
+            return Err("Connection closed".into());
+        }
+        if buf.trim() == "." { break; }
+        let path = match util::decode_path(buf.trim()) {
+            Some(path) => dir.join(path),
+            None => panic!("Child parse error: {}", buf.trim())
+        };
+        if state.exclude.is_skipped(&path) {
+            continue;
+        }
+        // Listed paths missing here may exist on other nodes
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into())
+        };
+        if meta.is_file() {
+            list_file(&mut state, &path, &meta).await?;
+        } else if meta.is_dir() {
+            traverse_dir(&mut state, path).await?;
+        }
+    }
+    list_partials(&mut state, opts).await?;
 
     println!(".");
     Ok(state)
@@ -147,95 +251,242 @@ async fn serve_read(dir: path::PathBuf, dump_state: &DumpState) -> Result<(), Bo
     let mut buf = String::new();
     loop {
         buf.clear();
-        io::stdin().read_line(&mut buf).expect("Failed to read");
+        if io::stdin().read_line(&mut buf)? == 0 {
+            return Err("Connection closed".into());
+        }
         if buf.trim() == "." { break; }
         chunks.push(String::from(buf.trim()));
     }
 
+    // The client may be gone (eg. timed out), so writes must not panic
+    let mut out = io::stdout();
     for chunk in &chunks {
-        let &fc_vec_opt = &dump_state.chunks.get(chunk);
-
-        match &fc_vec_opt {
-            Some(fc_vec) => {
-                let fc = &fc_vec[0];
-                let path = dir.join(&fc.path);
-                let mut f = afs::File::open(&path).await?;
-                let mut buf: Vec<u8> = vec![0; fc.size];
-                f.seek(io::SeekFrom::Start(fc.offset)).await?;
-                f.read(&mut buf).await?;
+        if !dump_state.chunks.contains_key(chunk) {
+            continue;
+        }
+        match dump_state.read_chunk(&dir, chunk).await? {
+            Some(buf) => {
                 let encoded = base64::encode(buf);
-                println!("C:{}", chunk);
+                writeln!(out, "C:{}", chunk)?;
                 for line in encoded.into_bytes().chunks(config::BASE64_LINE_LENGTH) {
-                    io::stdout().write_all(line)?;
-                    io::stdout().write_all(b"\n")?;
+                    out.write_all(line)?;
+                    out.write_all(b"\n")?;
                 }
-                println!(".");
+                writeln!(out, ".")?;
             },
-            None => {}
+            None => writeln!(out, "V:{}", chunk)?
         }
 
     }
-    println!(".");
+    writeln!(out, ".")?;
     Ok(())
 }
 
-async fn serve_write(dir: path::PathBuf, dump_state: &DumpState) -> Result<(), Box<dyn Error>> {
+// With continue_on_error a failed file operation is reported and skipped (returns false),
+// otherwise the error aborts
+fn check_file(res: Result<(), Box<dyn Error>>, path: &path::Path, opts: &ServeOptions) -> Result<bool, Box<dyn Error>> {
+    match res {
+        Ok(()) => Ok(true),
+        Err(e) if opts.continue_on_error => {
+            println!("ERROR {}: {}", path.display(), e);
+            Ok(false)
+        },
+        Err(e) => Err(e)
+    }
+}
+
+// Create the temp file the new version of fd is written to, returns its path
+fn stage_tmp(dump_state: &DumpState, fd: &FileData, opts: &ServeOptions) -> Result<path::PathBuf, Box<dyn Error>> {
+    let filepath = tmp_filename(&fd.path);
+    //eprintln!("CREATE {:?}", &filepath);
+    let res = (|| {
+        if let Some(parent) = fd.path.parent() {
+            if !parent.exists() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        // The mode is applied at commit, so read-only files can still be written.
+        // The file gets its final size up front, whatever order the chunks arrive in
+        with_writable_parent(&filepath, || fs::File::create(&filepath)?.set_len(fd.size))?;
+        Ok(())
+    })();
+    if !check_file(res, &fd.path, opts)? {
+        dump_state.failed.borrow_mut().insert(filepath.clone());
+    }
+    Ok(filepath)
+}
+
+// Copy a local chunk (LC) into the staged file, or put a remote one (RC) on the wait list
+async fn stage_chunk(dump_state: &DumpState, dir: &path::Path, filepath: &path::Path, local: bool, hc: &HashChunk) {
+    if local && dump_state.failed.borrow().contains(filepath) {
+        // The file already failed, don't bother
+    } else if local && dump_state.chunks.get(&hc.hash)
+            .is_some_and(|fc_vec| fc_vec.iter().any(|fc| fc.path == filepath && fc.offset == hc.offset)) {
+        // Already in place (when writing in place)
+    } else if local {
+        // Local chunk, copy it locally
+        let res = match dump_state.read_chunk(dir, &hc.hash).await {
+            Ok(Some(buf)) => dump_state.write_chunk(filepath, hc, &buf).await,
+            Ok(None) => Err(format!("Chunk not found: {}", hc.hash).into()),
+            Err(e) => Err(e)
+        };
+        if let Err(e) = res {
+            println!("ERROR {}", e);
+            dump_state.failed.borrow_mut().insert(filepath.to_path_buf());
+        }
+    } else {
+        // Remote chunk, add to wait list
+        let mut missing = dump_state.missing.borrow_mut();
+        let v = missing.entry(hc.hash.clone()).or_insert(Vec::new());
+        v.push(Box::new(FileChunk { path: filepath.to_path_buf(), offset: hc.offset, size: hc.size }));
+    }
+}
+
+// Whether any of the (offset, size) ranges of a overlaps one of b, the ranges within each list
+// must not overlap each other
+fn overlaps(mut a: Vec<(u64, usize)>, mut b: Vec<(u64, usize)>) -> bool {
+    a.sort();
+    b.sort();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let (a_start, a_end) = (a[i].0, a[i].0 + a[i].1 as u64);
+        let (b_start, b_end) = (b[j].0, b[j].0 + b[j].1 as u64);
+        if a_start < b_end && b_start < a_end {
+            return true;
+        }
+        if a_end <= b_end { i += 1 } else { j += 1 }
+    }
+    false
+}
+
+// Write the chunks straight into the existing file. When the new data would overwrite a part
+// of the file that a local chunk is still to be copied from (eg. content shifted by an insert),
+// the file goes through a temp file instead.
+async fn stage_inplace(dump_state: &DumpState, dir: &path::Path, fd: Box<FileData>, chunks: &[(bool, HashChunk)], opts: &ServeOptions) -> Result<(), Box<dyn Error>> {
+    let listed = |hash: &str| dump_state.chunks.get(hash).into_iter().flatten().filter(|fc| fc.path == fd.path);
+    let writes: Vec<&(bool, HashChunk)> = chunks.iter()
+        .filter(|(_, hc)| !listed(&hc.hash).any(|fc| fc.offset == hc.offset))
+        .collect();
+    let mut sources: Vec<(u64, usize)> = writes.iter()
+        .filter(|(local, _)| *local)
+        .flat_map(|(_, hc)| listed(&hc.hash).map(|fc| (fc.offset, fc.size)))
+        .collect();
+    sources.sort();
+    sources.dedup();
+    let filepath = if overlaps(writes.iter().map(|(_, hc)| (hc.offset, hc.size)).collect(), sources) {
+        stage_tmp(dump_state, &fd, opts)?
+    } else {
+        fd.path.clone()
+    };
+    for (local, hc) in chunks {
+        stage_chunk(dump_state, dir, &filepath, *local, hc).await;
+    }
+    dump_state.rename.borrow_mut().insert(filepath, fd);
+    Ok(())
+}
+
+async fn serve_write(dir: path::PathBuf, dump_state: &DumpState, opts: &ServeOptions, input: &mut dyn io::BufRead) -> Result<(), Box<dyn Error>> {
     let mut buf = String::new();
 
-    let mut file: Option<afs::File> = None;
+    let mut in_file = false;
     let mut filepath = path::PathBuf::from("");
+    // The current file is not written, its chunks are ignored
+    let mut skip_file = false;
+    // File to write in place, with its chunks (and whether they are local)
+    let mut inplace: Option<Box<FileData>> = None;
+    let mut inplace_chunks: Vec<(bool, HashChunk)> = Vec::new();
     loop {
         buf.clear();
-        io::stdin().read_line(&mut buf).expect("Failed to read");
+        if input.read_line(&mut buf)? == 0 {
+            return Err("Connection closed".into());
+        }
         let fields: Vec<&str> = buf.trim().split(':').collect();
+        if opts.read_only && fields[0] != "." {
+            return Err(format!("Refusing to write to a read-only location: {}", buf.trim()).into());
+        }
+        // Other nodes don't know what is protected here (eg. their ignore rules or markers differ)
+        let target = match fields[0] {
+            "FM" | "FD" | "D" | "X" => fields.get(1).and_then(|path| util::decode_path(path)),
+            _ => None
+        };
+        let refused = target.and_then(|path| {
+            let exclude = &dump_state.exclude;
+            let why = if exclude.in_marked(&path) {
+                format!("it is in a directory marked with {}", exclude.marker.as_deref().unwrap_or_default())
+            } else if exclude.is_skipped(&path) {
+                String::from("it is excluded here")
+            } else {
+                return None;
+            };
+            Some((path, why))
+        });
+        if let Some((path, why)) = refused {
+            println!("ERROR {}: Not written, {}", path.display(), why);
+            if fields[0] == "FD" {
+                in_file = true;
+                skip_file = true;
+            }
+            if fields[0] == "FD" || fields[0] == "FM" {
+                dump_state.protected.borrow_mut().insert(path);
+            }
+            continue;
+        }
 
         match fields[0] {
             "FM" | "FD" => {
-                let path = path::PathBuf::from(fields[1]);
-                let fd = Box::new(FileData {
-                    path: path.clone(),
-                    mode: fields[2].parse().expect("Child parse error"),
-                    user: fields[3].parse().expect("Child parse error"),
-                    group: fields[4].parse().expect("Child parse error"),
-                    size: fields[5].parse().expect("Child parse error"),
-                    mtime: fields[6].parse().expect("Child parse error"),
-                    chunks: vec![]
-                });
-                if fields[0] == "FD" {
-                    filepath = path.clone();
-                    let mut filename = path.file_name().expect("Protocol error!").to_os_string();
-                    filename.push(".SyNcR-TmP");
-                    filepath.set_file_name(filename);
-                    //eprintln!("CREATE {:?}", &filepath);
-                    file = Some(afs::File::create(&filepath).await?);
-                    afs::set_permissions(&filepath, afs::Permissions::from_mode(fd.mode)).await?;
-                    dump_state.rename.borrow_mut().insert(filepath.clone(), path.clone());
+                let fd = Box::new(FileData::from_fields(&fields).expect("Child parse error"));
+                if fields[0] == "FD" && opts.inplace && fd.path.is_file() {
+                    // Whether it can really be written in place is decided when all its chunks are known
+                    filepath = fd.path.clone();
+                    in_file = true;
+                    inplace = Some(fd);
+                } else if fields[0] == "FD" {
+                    filepath = stage_tmp(dump_state, &fd, opts)?;
+                    in_file = true;
+                    dump_state.rename.borrow_mut().insert(filepath.clone(), fd);
+                } else {
+                    dump_state.meta.borrow_mut().push(fd);
+                }
+            },
+            "D" => {
+                let dd = Box::new(DirData::from_fields(&fields).expect("Child parse error"));
+                let created = !dd.path.is_dir();
+                if created {
+                    let res = with_writable_parent(&dd.path, || fs::create_dir_all(&dd.path));
+                    if !check_file(res, &dd.path, opts)? {
+                        continue;
+                    }
+                }
+                dump_state.dirs.borrow_mut().push((dd, created));
+            },
+            "X" => {
+                // The source of the file vanished, drop it
+                let path = util::decode_path(fields[1]).expect("Child parse error");
+                let tmp = tmp_filename(&path);
+                let staged = if dump_state.rename.borrow().contains_key(&tmp) { tmp } else { path };
+                if let Some(fd) = dump_state.rename.borrow_mut().remove(&staged) {
+                    if staged == fd.path {
+                        println!("ERROR {}: Left partially updated, it was written in place", fd.path.display());
+                    }
+                    discard(&staged, &fd)?;
                 }
+                dump_state.failed.borrow_mut().remove(&staged);
+                for fc_vec in dump_state.missing.borrow_mut().values_mut() {
+                    fc_vec.retain(|fc| fc.path != staged);
+                }
+                dump_state.missing.borrow_mut().retain(|_, fc_vec| !fc_vec.is_empty());
             },
             "LC" | "RC" => {
-                if file.is_none() {
+                if !in_file {
                     panic!("Protocol error!");
                 }
-                let hc = Box::new(HashChunk {
-                    hash: String::from(fields[3]),
-                    offset: fields[1].parse().expect("Child parse error"),
-                    size: fields[2].parse().expect("Child parse error")
-                });
-                if fields[0] == "LC" {
-                    // Local chunk, copy it locally
-                    let buf = dump_state.read_chunk(&dir, fields[3]).await?.expect("Chunk not found");
-                    if let Err(e) = dump_state.write_chunk(&filepath, &hc, &buf).await {
-                        println!("ERROR {}", e);
-                    }
+                let hc = HashChunk::from_fields(&fields).expect("Child parse error");
+                if skip_file {
+                    // Not written
+                } else if inplace.is_some() {
+                    inplace_chunks.push((fields[0] == "LC", hc));
                 } else {
-                    // Remote chunk, add to wait list
-                    let mut missing = dump_state.missing.borrow_mut();
-                    let v = missing.entry(String::from(fields[3])).or_insert(Vec::new());
-                    v.push(Box::new(FileChunk {
-                        path: filepath.clone(),
-                        offset: fields[1].parse().expect("Child parse error"),
-                        size: fields[2].parse().expect("Child parse error")
-                    }));
+                    stage_chunk(dump_state, &dir, &filepath, fields[0] == "LC", &hc).await;
                 }
             },
             "C" => {
@@ -244,12 +495,17 @@ async fn serve_write(dir: path::PathBuf, dump_state: &DumpState) -> Result<(), B
                 let mut chunk: Vec<u8> = Vec::new();
                 loop {
                     buf.clear();
-                    io::stdin().read_line(&mut buf).expect("Failed to read");
+                    if input.read_line(&mut buf)? == 0 {
+                        return Err("Connection closed".into());
+                    }
                     if buf.trim() == "." {
                         break;
                     }
                     //eprintln!("DECODE: [{:?}]", &buf.trim());
                     chunk.append(&mut base64::decode(&buf.trim())?);
+                    if chunk.len() > config::MAX_CHUNK_SIZE {
+                        return Err(format!("Chunk too large: {}", hash).into());
+                    }
                 }
                 //eprintln!("DECODED CHUNK: {:?}", chunk);
                 let mut missing = dump_state.missing.borrow_mut();
@@ -264,6 +520,7 @@ async fn serve_write(dir: path::PathBuf, dump_state: &DumpState) -> Result<(), B
                             //let filepath = tmp_filename(&fc.path);
                             if let Err(e) = dump_state.write_chunk(&fc.path, &hc, &chunk).await {
                                 eprintln!("ERROR WRITING {}", e);
+                                dump_state.failed.borrow_mut().insert(fc.path.clone());
                             }
                         }
                         missing.remove(hash);
@@ -272,8 +529,13 @@ async fn serve_write(dir: path::PathBuf, dump_state: &DumpState) -> Result<(), B
                 }
             },
             "." => {
-                if file.is_some() {
-                    file = None;
+                if in_file {
+                    skip_file = false;
+                    if let Some(fd) = inplace.take() {
+                        stage_inplace(dump_state, &dir, fd, &inplace_chunks, opts).await?;
+                        inplace_chunks.clear();
+                    }
+                    in_file = false;
                 } else {
                     break;
                 }
@@ -285,53 +547,544 @@ async fn serve_write(dir: path::PathBuf, dump_state: &DumpState) -> Result<(), B
     Ok(())
 }
 
-async fn serve_commit(_FIXME_dir: path::PathBuf, dump_state: &DumpState) -> Result<(), Box<dyn Error>> {
-    if dump_state.missing.borrow().len() > 0 {
-        eprintln!("FIXME: ERROR");
+// Re-chunk files and compare them with the expected chunk lists (V:<path> followed by C lines,
+// until "."), mismatching or unreadable files are reported with BAD:<path>
+async fn serve_verify() -> Result<(), Box<dyn Error>> {
+    let mut expected: Vec<(path::PathBuf, Vec<HashChunk>)> = Vec::new();
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if io::stdin().read_line(&mut buf)? == 0 {
+            return Err("Connection closed".into());
+        }
+        let fields: Vec<&str> = buf.trim().split(':').collect();
+        match fields[0] {
+            "V" => expected.push((util::decode_path(fields[1]).expect("Child parse error"), Vec::new())),
+            "C" => match expected.last_mut() {
+                Some((_, chunks)) => chunks.push(HashChunk::from_fields(&fields).expect("Child parse error")),
+                None => panic!("Protocol error!")
+            },
+            "." => break,
+            _ => panic!("Child parse error: {}", buf.trim())
+        }
+    }
+    for (path, chunks) in &expected {
+        let mut actual = Vec::new();
+        let res = chunk_file(path, |offset, size, hash| actual.push(HashChunk { hash, offset, size })).await;
+        if res.is_err() || actual != *chunks {
+            println!("BAD:{}", util::encode_path(path));
+        }
+    }
+    println!(".");
+    Ok(())
+}
+
+// Apply (depending on options) timestamps and mode of fd to the file at path
+fn apply_meta(path: &path::Path, fd: &FileData, opts: &ServeOptions) -> io::Result<()> {
+    if opts.times || opts.atimes {
+        let mut times = fs::FileTimes::new()
+            .set_modified(time::UNIX_EPOCH + time::Duration::from_secs(fd.mtime as u64));
+        if opts.atimes {
+            times = times.set_accessed(time::UNIX_EPOCH + time::Duration::from_secs(fd.atime as u64));
+        }
+        fs::File::open(path)?.set_times(times)?;
     }
-    for (src, dst) in dump_state.rename.borrow().iter() {
-        //eprintln!("RENAME: {:?} -> {:?}", src, dst);
-        afs::rename(&src, &dst).await?;
-        //fs::rename(&src, &dst)?;
+    // The mode goes last: writes and ownership changes clear setuid/setgid
+    fs::set_permissions(path, fs::Permissions::from_mode(fd.mode))?;
+    let special = fd.mode & 0o7000;
+    if special != 0 && fs::metadata(path)?.permissions().mode() & 0o7000 != special {
+        // Eg. setgid for a group we are not a member of is silently dropped
+        eprintln!("Cannot set special mode bits {:o} on {}", special, fd.path.display());
+    }
+    Ok(())
+}
+
+// Replace the file with its staged version, files written in place only get their size and
+// metadata
+fn commit_file(src: &path::Path, fd: &FileData, opts: &ServeOptions) -> Result<(), Box<dyn Error>> {
+    if src == fd.path {
+        fs::OpenOptions::new().write(true).open(src)?.set_len(fd.size)?;
+        Ok(apply_meta(src, fd, opts)?)
+    } else {
+        apply_meta(src, fd, opts)?;
+        with_writable_parent(&fd.path, || fs::rename(src, &fd.path))
+    }
+}
+
+// Remove the temp file of a staged file (files written in place are kept)
+fn discard(staged: &path::Path, fd: &FileData) -> Result<(), Box<dyn Error>> {
+    if staged != fd.path && staged.exists() {
+        with_writable_parent(staged, || fs::remove_file(staged))?;
+    }
+    Ok(())
+}
+
+// Where the partial file of path is kept
+fn partial_path(partial_dir: &str, path: &path::Path) -> path::PathBuf {
+    path::Path::new(partial_dir).join(path.strip_prefix(".").unwrap_or(path))
+}
+
+// Move the temp file of an incomplete file to the partial dir (if there is one), so its data can
+// be reused by the next sync. Otherwise it is discarded.
+fn keep_partial(staged: &path::Path, fd: &FileData, opts: &ServeOptions) -> Result<(), Box<dyn Error>> {
+    if let (Some(partial_dir), true) = (&opts.partial_dir, staged != fd.path && staged.exists()) {
+        let partial = partial_path(partial_dir, &fd.path);
+        if let Some(parent) = partial.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::rename(staged, &partial).is_ok() {
+            return Ok(());
+        }
+    }
+    discard(staged, fd)
+}
+
+// Drop the temp files of an uncommitted WRITE session
+fn discard_staged(dump_state: &DumpState, opts: &ServeOptions) {
+    for (src, fd) in dump_state.rename.borrow().iter() {
+        keep_partial(src, fd, opts).ok();
+    }
+}
+
+async fn serve_commit(_FIXME_dir: path::PathBuf, dump_state: &DumpState, opts: &ServeOptions) -> Result<(), Box<dyn Error>> {
+    // Files with chunks that failed or never arrived can't be committed
+    let mut incomplete = dump_state.failed.borrow().clone();
+    for fc_vec in dump_state.missing.borrow().values() {
+        incomplete.extend(fc_vec.iter().map(|fc| fc.path.clone()));
+    }
+    for (src, fd) in dump_state.rename.borrow().iter() {
+        if incomplete.contains(src) {
+            println!("FAILED:{}", util::encode_path(&fd.path));
+        }
+    }
+    // Protected files were skipped on purpose, they don't abort the commit
+    for path in dump_state.protected.borrow().iter() {
+        println!("FAILED:{}", util::encode_path(path));
+    }
+    if !incomplete.is_empty() && !opts.skip_failed && !opts.continue_on_error {
+        for (src, fd) in dump_state.rename.borrow().iter() {
+            if *src == fd.path && incomplete.contains(src) {
+                println!("ERROR {}: Left partially updated, it was written in place", fd.path.display());
+            } else if *src == fd.path {
+                // Its new contents are already there, at least leave it consistent
+                commit_file(src, fd, opts).ok();
+            } else if incomplete.contains(src) {
+                keep_partial(src, fd, opts)?;
+            } else {
+                discard(src, fd)?;
+            }
+        }
+        println!("ABORTED");
+        return Ok(());
+    }
+    for (src, fd) in dump_state.rename.borrow().iter() {
+        //eprintln!("RENAME: {:?} -> {:?}", src, fd.path);
+        if *src == fd.path && incomplete.contains(src) {
+            println!("ERROR {}: Left partially updated, it was written in place", fd.path.display());
+            continue;
+        } else if incomplete.contains(src) {
+            keep_partial(src, fd, opts)?;
+            continue;
+        }
+        let res = commit_file(src, fd, opts);
+        if !check_file(res, &fd.path, opts)? {
+            println!("FAILED:{}", util::encode_path(&fd.path));
+            discard(src, fd).ok();
+        } else if let Some(partial_dir) = &opts.partial_dir {
+            // Its partial file from an earlier sync is no longer needed
+            fs::remove_file(partial_path(partial_dir, &fd.path)).ok();
+        }
+    }
+    for fd in dump_state.meta.borrow().iter() {
+        if !check_file(apply_meta(&fd.path, fd, opts).map_err(|e| e.into()), &fd.path, opts)? {
+            println!("FAILED:{}", util::encode_path(&fd.path));
+        }
+    }
+    // Created directories get their mode last, so read-only ones could be filled
+    for (dd, _) in dump_state.dirs.borrow().iter().rev().filter(|(_, created)| *created) {
+        check_file(fs::set_permissions(&dd.path, fs::Permissions::from_mode(dd.mode)).map_err(|e| e.into()), &dd.path, opts)?;
+    }
+    // Writing the contents changed the directory mtimes, so they are set at the very end, deepest
+    // first
+    if opts.times || opts.atimes {
+        let dirs = dump_state.dirs.borrow();
+        let mut dirs: Vec<&DirData> = dirs.iter().map(|(dd, _)| dd.as_ref()).collect();
+        dirs.sort_by_key(|dd| cmp::Reverse(dd.path.components().count()));
+        for dd in dirs {
+            let mtime = time::UNIX_EPOCH + time::Duration::from_secs(dd.mtime as u64);
+            let res = fs::File::open(&dd.path).and_then(|f| f.set_times(fs::FileTimes::new().set_modified(mtime)));
+            check_file(res.map_err(|e| e.into()), &dd.path, opts)?;
+        }
     }
     println!("OK");
     Ok(())
 }
 
-pub fn serve(dir: &str) -> Result<(), Box<dyn Error>> {
-    env::set_current_dir(&dir)?;
-    println!("VERSION:1");
+// Free space available to unprivileged users on the filesystem of path
+fn available_space(path: &path::Path) -> io::Result<u64> {
+    let cpath = ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/////////////
+// Recover //
+/////////////
+fn find_files(dir: &path::Path, found: &mut Vec<path::PathBuf>, filter: &dyn Fn(&path::Path) -> bool) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.is_dir() {
+            find_files(&path, found, filter)?;
+        } else if meta.is_file() && filter(&path) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+// List temp files left behind by an interrupted sync, remove them when clean is set
+pub fn recover(dir: &str, clean: bool) -> Result<(), Box<dyn Error>> {
+    let mut found = Vec::new();
+    find_files(path::Path::new(dir), &mut found, &|path| path.to_string_lossy().ends_with(config::TMP_SUFFIX))?;
+    for path in &found {
+        let size = fs::metadata(path)?.len();
+        println!("{} ({} bytes)", path.display(), size);
+        if clean {
+            fs::remove_file(path)?;
+        }
+    }
+    if clean {
+        eprintln!("Removed {} temp files", found.len());
+    } else {
+        eprintln!("Found {} temp files, use --clean to remove them", found.len());
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct ServeOptions {
+    pub mkdirs: bool,
+    pub times: bool,
+    pub atimes: bool,
+    pub exclude_hidden: bool,
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+    pub no_builtin_excludes: bool,
+    pub nosync_marker: Option<String>,
+    pub partial_dir: Option<String>,
+    pub read_only: bool,
+    pub skip_failed: bool,
+    pub continue_on_error: bool,
+    pub inplace: bool
+}
+
+pub fn serve(dir: &str, opts: &ServeOptions) -> Result<(), Box<dyn Error>> {
+    if opts.mkdirs && !opts.read_only && !path::Path::new(dir).exists() {
+        fs::create_dir_all(dir)?;
+    }
+    if let Err(e) = env::set_current_dir(&dir) {
+        println!("E:NO-DIR: Cannot open directory {}: {}", dir, e);
+        return Err(e.into());
+    }
+    println!("VERSION:{}", config::PROTOCOL_VERSION);
+    println!("TIME:{}", util::unix_time());
     println!(".");
 
     let mut dump_state: Option<DumpState> = None;
 
     loop {
         let mut cmdline = String::new();
-        io::stdin().read_line(&mut cmdline).expect("Failed to read command");
+        if io::stdin().read_line(&mut cmdline).unwrap_or(0) == 0 {
+            // The client is gone (or unreadable), whatever it staged won't be committed
+            if let Some(state) = &dump_state {
+                discard_staged(state, opts);
+            }
+            break;
+        }
 
-        match &cmdline.trim()[..] {
-            "LIST" => dump_state = Some(serve_list(path::PathBuf::from("."))?),
+        let res: Result<(), Box<dyn Error>> = match &cmdline.trim()[..] {
+            "LIST" => serve_list(path::PathBuf::from("."), opts).map(|state| dump_state = Some(state)),
+            "LISTF" => task::block_on(serve_list_files(path::PathBuf::from("."), opts)).map(|state| dump_state = Some(state)),
             "READ" => match &dump_state {
-                Some(state) => task::block_on(serve_read(path::PathBuf::from("."), &state))?,
+                Some(state) => task::block_on(serve_read(path::PathBuf::from("."), state)),
                 None => {
                     println!("!Use LIST command first!");
+                    Ok(())
                 }
             },
             "WRITE" => match &dump_state {
-                Some(state) => task::block_on(serve_write(path::PathBuf::from("."), &state))?,
+                Some(state) => task::block_on(serve_write(path::PathBuf::from("."), state, opts, &mut io::stdin().lock())),
                 None => {
                     println!("!Use LIST command first!");
+                    Ok(())
                 }
             },
             "COMMIT" => match &dump_state {
-                Some(state) => task::block_on(serve_commit(path::PathBuf::from("."), &state))?,
+                Some(state) => task::block_on(serve_commit(path::PathBuf::from("."), state, opts)),
                 None => {
                     println!("!Use LIST command first!");
+                    Ok(())
                 }
             },
+            "VERIFY" => task::block_on(serve_verify()),
+            "SPACE" => available_space(path::Path::new("."))
+                .and_then(|space| writeln!(io::stdout(), "SPACE:{}", space)).map_err(|e| e.into()),
+            "PING" => writeln!(io::stdout(), "PONG").map_err(|e| e.into()),
             "QUIT" => break,
-            _ => println!("E:UNK-CMD: Unknown command: {}", &cmdline.trim())
+            _ => {
+                println!("E:UNK-CMD: Unknown command: {}", &cmdline.trim());
+                Ok(())
+            }
+        };
+        // Whatever command failed (eg. the client went away mid-transfer), the staged files
+        // won't be committed
+        if let Err(e) = res {
+            if let Some(state) = &dump_state {
+                discard_staged(state, opts);
+            }
+            return Err(e);
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_overlaps() {
+        assert!(!overlaps(vec![], vec![(0, 10)]));
+        assert!(!overlaps(vec![(0, 10), (20, 10)], vec![(10, 10), (30, 5)]));
+        assert!(overlaps(vec![(20, 10), (0, 10)], vec![(25, 10)]));
+        // Content shifted by an insert: the chunk moving to 5 covers the source of the next one
+        assert!(overlaps(vec![(0, 5), (5, 10), (15, 10)], vec![(0, 10), (10, 10)]));
+    }
+
+    // Update a file of an "a" and a "b" chunk with --inplace to the given chunks (and whether
+    // they are local). Returns whether it was written in place, whether it kept its inode and
+    // the new contents.
+    fn update_inplace(name: &str, chunks: &[(bool, &[u8])]) -> (bool, bool, Vec<u8>) {
+        let dir = env::temp_dir().join(format!("syncr-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        let (a, b) = (vec![b'a'; 1000], vec![b'b'; 1000]);
+        fs::write(&path, [a.as_slice(), b.as_slice()].concat()).unwrap();
+        let ino = fs::metadata(&path).unwrap().ino();
+
+        let opts = ServeOptions { inplace: true, ..Default::default() };
+        let mut state = new_dump_state(&dir, &opts).unwrap();
+        state.add_chunk(util::hash(&a), path.clone(), 0, a.len());
+        state.add_chunk(util::hash(&b), path.clone(), a.len() as u64, b.len());
+        let mut offset = 0;
+        let staged: Vec<(bool, HashChunk)> = chunks.iter().map(|(local, data)| {
+            offset += data.len() as u64;
+            (*local, HashChunk { hash: util::hash(data), offset: offset - data.len() as u64, size: data.len() })
+        }).collect();
+        let fd = Box::new(FileData { path: path.clone(), mode: 0o644, user: 0, group: 0, size: offset, mtime: 0, atime: 0, chunks: Vec::new() });
+        task::block_on(stage_inplace(&state, &dir, fd, &staged, &opts)).unwrap();
+        assert!(state.failed.borrow().is_empty());
+
+        // Deliver the remote chunks, then commit
+        for ((local, data), (_, hc)) in chunks.iter().zip(&staged) {
+            if !local {
+                let fc = state.missing.borrow_mut().remove(&hc.hash).unwrap().pop().unwrap();
+                task::block_on(state.write_chunk(&fc.path, hc, &data.to_vec())).unwrap();
+            }
+        }
+        let (src, fd) = state.rename.borrow_mut().pop_first().unwrap();
+        commit_file(&src, &fd, &opts).unwrap();
+        let res = (src == path, fs::metadata(&path).unwrap().ino() == ino, fs::read(&path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        res
+    }
+
+    #[test]
+    fn test_inplace() {
+        let (a, b, c) = (vec![b'a'; 1000], vec![b'b'; 1000], vec![b'c'; 500]);
+        // Appended data: the old chunks stay where they are
+        let (inplace, same_inode, contents) = update_inplace("inplace", &[(true, &a), (true, &b), (false, &c)]);
+        assert!(inplace && same_inode);
+        assert_eq!(contents, [a.as_slice(), &b, &c].concat());
+        // Shrunk file
+        let (inplace, same_inode, contents) = update_inplace("inplace-shrink", &[(true, &b)]);
+        assert!(inplace && same_inode);
+        assert_eq!(contents, b);
+    }
+
+    #[test]
+    fn test_inplace_shifted() {
+        // Data inserted at the start moves "a" over the source of "b", so it goes through a temp file
+        let (a, b, c) = (vec![b'a'; 1000], vec![b'b'; 1000], vec![b'c'; 500]);
+        let (inplace, _, contents) = update_inplace("inplace-shifted", &[(false, &c), (true, &a), (true, &b)]);
+        assert!(!inplace);
+        assert_eq!(contents, [c.as_slice(), &a, &b].concat());
+    }
+
+    // Run a WRITE session with the given lines on a fresh state
+    fn write_session(dir: &path::Path, opts: &ServeOptions, lines: &[String]) -> DumpState {
+        let state = new_dump_state(dir, opts).unwrap();
+        let mut input = io::Cursor::new(lines.join("\n") + "\n.\n");
+        task::block_on(serve_write(dir.to_path_buf(), &state, opts, &mut input)).unwrap();
+        state
+    }
+
+    #[test]
+    fn test_write_marked_dir() {
+        let dir = env::temp_dir().join(format!("syncr-test-marked-{}", std::process::id()));
+        fs::create_dir_all(dir.join("d")).unwrap();
+        fs::write(dir.join("d").join(config::NOSYNC_MARKER), "").unwrap();
+        let path = dir.join("d/f");
+        fs::write(&path, "old").unwrap();
+
+        let opts = ServeOptions { nosync_marker: Some(String::from(config::NOSYNC_MARKER)), ..Default::default() };
+        let state = write_session(&dir, &opts, &[
+            format!("FD:{}:33188:0:0:3:0:0", util::encode_path(&path)),
+            format!("RC:0:3:{}", util::hash(b"new")),
+            String::from("."),
+            format!("D:{}:16877:0:0:0", util::encode_path(&dir.join("d/sub")))
+        ]);
+        let written = (fs::read_to_string(&path).unwrap(), tmp_filename(&path).exists(), dir.join("d/sub").exists());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(written, (String::from("old"), false, false));
+        assert!(state.rename.borrow().is_empty() && state.missing.borrow().is_empty());
+        assert!(state.protected.borrow().contains(&path));
+    }
+
+    #[test]
+    fn test_write_excluded() {
+        let dir = env::temp_dir().join(format!("syncr-test-excluded-{}", std::process::id()));
+        fs::create_dir_all(dir.join("logs")).unwrap();
+        fs::write(dir.join(config::IGNORE_FILE), "logs\n*.bak\n").unwrap();
+        let (log, bak, file) = (dir.join("logs/a.log"), dir.join("b.bak"), dir.join("c"));
+        fs::write(&log, "old").unwrap();
+        fs::write(&bak, "old").unwrap();
+
+        let opts = ServeOptions::default();
+        let state = write_session(&dir, &opts, &[
+            format!("FD:{}:33188:0:0:3:0:0", util::encode_path(&log)),
+            format!("RC:0:3:{}", util::hash(b"new")),
+            String::from("."),
+            format!("FM:{}:33152:0:0:3:0:0", util::encode_path(&bak)),
+            format!("FD:{}:33188:0:0:3:0:0", util::encode_path(&file)),
+            format!("RC:0:3:{}", util::hash(b"new")),
+            String::from(".")
+        ]);
+        let unchanged = (fs::read_to_string(&log).unwrap(), tmp_filename(&log).exists());
+        let staged = tmp_filename(&file).exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(unchanged, (String::from("old"), false));
+        assert!(staged);
+        assert_eq!(state.protected.borrow().len(), 2);
+        assert!(state.meta.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_with_writable_parent() {
+        let dir = env::temp_dir().join(format!("syncr-test-writable-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+        let mode = || fs::metadata(&dir).unwrap().permissions().mode() & 0o777;
+        // Root may write anyway, so the op itself refuses while the directory is read-only
+        let path = dir.join("file");
+        let write = || match mode() & 0o200 {
+            0 => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+            _ => fs::write(&path, "data")
+        };
+        let res = with_writable_parent(&path, write);
+        let after_write = mode();
+        let res_err = with_writable_parent(&path, || Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied)));
+        let after_err = mode();
+        let written = fs::read_to_string(&path);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(res.is_ok());
+        assert_eq!(written.unwrap(), "data");
+        assert!(res_err.is_err());
+        assert_eq!((after_write, after_err), (0o555, 0o555));
+    }
+
+    #[test]
+    fn test_stage_tmp() {
+        let dir = env::temp_dir().join(format!("syncr-test-stage-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("notadir"), "").unwrap();
+        let opts = ServeOptions { continue_on_error: true, ..Default::default() };
+        let state = new_dump_state(&dir, &opts).unwrap();
+        let fd = |path: &str| FileData { path: dir.join(path), mode: 0o644, user: 0, group: 0, size: 5000, mtime: 0, atime: 0, chunks: Vec::new() };
+
+        // The temp file gets its final size up front, and is removed on discard
+        let (file, failing) = (fd("sub/file"), fd("notadir/file"));
+        let staged = stage_tmp(&state, &file, &opts).unwrap();
+        let len = fs::metadata(&staged).map(|meta| meta.len()).ok();
+        discard(&staged, &file).unwrap();
+        let discarded = !staged.exists();
+        // A failed stage leaves nothing behind and marks the file failed
+        let failed = stage_tmp(&state, &failing, &opts).unwrap();
+        let failed_exists = failed.exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(staged, tmp_filename(&file.path));
+        assert_eq!(len, Some(5000));
+        assert!(discarded);
+        assert!(!failed_exists && state.failed.borrow().contains(&failed));
+    }
+
+    #[test]
+    fn test_recover() {
+        let dir = env::temp_dir().join(format!("syncr-test-recover-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let files = [String::from("a"), String::from("sub/b"), String::from("sub/c.SyNcR")];
+        let temps = [format!("a{}", config::TMP_SUFFIX), format!("sub/b{}", config::TMP_SUFFIX)];
+        for file in files.iter().chain(&temps) {
+            fs::write(dir.join(file), "data").unwrap();
+        }
+        let exists = |paths: &[String]| paths.iter().map(|p| dir.join(p).exists()).collect::<Vec<_>>();
+
+        let mut found = Vec::new();
+        find_files(&dir, &mut found, &|path| path.to_string_lossy().ends_with(config::TMP_SUFFIX)).unwrap();
+        found.sort();
+        recover(dir.to_str().unwrap(), false).unwrap();
+        let listed = (exists(&files), exists(&temps));
+        recover(dir.to_str().unwrap(), true).unwrap();
+        let cleaned = (exists(&files), exists(&temps));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, temps.iter().map(|t| dir.join(t)).collect::<Vec<_>>());
+        assert_eq!(listed, (vec![true; 3], vec![true; 2]));
+        assert_eq!(cleaned, (vec![true; 3], vec![false; 2]));
+    }
+
+    #[test]
+    fn test_read_chunk_vanished() {
+        let dir = env::temp_dir().join(format!("syncr-test-vanished-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let data = b"chunk data";
+        fs::write(&a, data).unwrap();
+        fs::write(&b, data).unwrap();
+        let hash = util::hash(data);
+        let mut state = new_dump_state(&dir, &ServeOptions::default()).unwrap();
+        state.add_chunk(hash.clone(), a.clone(), 0, data.len());
+        state.add_chunk(hash.clone(), b.clone(), 0, data.len());
+        let read = |state: &DumpState| task::block_on(state.read_chunk(&dir, &hash)).unwrap();
+
+        let intact = read(&state);
+        // Changed since listing, the other file still has it
+        fs::write(&a, b"chunk DATA").unwrap();
+        let changed = read(&state);
+        // Changed and vanished everywhere
+        fs::remove_file(&b).unwrap();
+        let vanished = read(&state);
+        fs::remove_file(&a).unwrap();
+        let removed = read(&state);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(intact.as_deref(), Some(&data[..]));
+        assert_eq!(changed.as_deref(), Some(&data[..]));
+        assert_eq!(vanished, None);
+        assert_eq!(removed, None);
+        assert_eq!(task::block_on(state.read_chunk(&dir, "unknown")).unwrap(), None);
+    }
+}
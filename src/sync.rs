@@ -1,24 +1,199 @@
 use async_process;
 use async_std::{prelude::*};
 use futures::future;
-use std::cell::RefCell;
+use async_std::task;
+use std::cell::{Cell, RefCell};
 use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
 use std::error::Error;
-use std::{path, pin::Pin};
+use std::{fmt, fs, path, pin::Pin, str, time};
 
-use crate::types::{HashChunk, FileData};
+use crate::error::ProtocolError;
+use crate::types::{HashChunk, FileData, DirData};
+use crate::config;
 use crate::connect;
+use crate::util;
 
 //////////
 // Sync //
 //////////
+// Parts of the sync that can be limited with --phase-timeout (transfer covers everything from the
+// diff up to the commit)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Connect,
+    Collect,
+    Transfer,
+    Commit
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Phase::Connect => "connect",
+            Phase::Collect => "collect",
+            Phase::Transfer => "transfer",
+            Phase::Commit => "commit"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl str::FromStr for Phase {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Phase, String> {
+        match name {
+            "connect" => Ok(Phase::Connect),
+            "collect" => Ok(Phase::Collect),
+            "transfer" => Ok(Phase::Transfer),
+            "commit" => Err(String::from("The commit phase can't be limited, it always runs to completion")),
+            _ => Err(format!("Unknown phase: {} (expected connect, collect or transfer)", name))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SyncOptions {
+    pub dry_run: bool,
+    pub continue_on_error: bool,
+    pub debug_connect: bool,
+    pub delay_updates: bool,
+    pub exclude_hidden: bool,
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+    pub files_from: Option<Vec<String>>,
+    pub human_readable: bool,
+    pub binary_units: bool,
+    pub list_only: bool,
+    pub labels: BTreeMap<usize, String>,
+    pub inplace: bool,
+    pub keepalive: u64,
+    pub max_connections: usize,
+    pub min_free: u64,
+    pub mkdirs: bool,
+    pub no_builtin_excludes: bool,
+    pub no_data: bool,
+    pub nosync_marker: Option<String>,
+    pub no_times: bool,
+    pub partial_dir: Option<String>,
+    pub phase_timeouts: BTreeMap<Phase, u64>,
+    pub post_sync: Option<String>,
+    pub protocol_version: Option<u32>,
+    pub prune_empty_dirs: bool,
+    pub read_only: BTreeSet<usize>,
+    pub relative: bool,
+    pub times: bool,
+    pub atimes: bool,
+    pub timeout: u64,
+    pub strict_clock: bool,
+    pub subpaths: Vec<String>,
+    pub trace_chunks: bool,
+    pub skip_failed: bool,
+    pub trailing_slash: bool,
+    pub verify_after: bool
+}
+
+impl SyncOptions {
+    fn format_size(&self, bytes: u64) -> String {
+        if self.human_readable {
+            util::format_bytes(bytes, self.binary_units)
+        } else {
+            format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+        }
+    }
+
+    // Whether the path is within the parts of the tree selected with --subpath
+    fn in_subpaths(&self, path: &path::Path) -> bool {
+        self.subpaths.is_empty() || self.subpaths.iter().any(|subpath| path.starts_with(subpath))
+    }
+
+    // Bytes scanned and the scanning throughput in the given time
+    fn scan_rate(&self, scanned: u64, elapsed: time::Duration) -> String {
+        let secs = elapsed.as_secs_f64();
+        let rate = if secs > 0.0 { (scanned as f64 / secs) as u64 } else { 0 };
+        format!("scanned {} in {:.1} s ({}/s)", self.format_size(scanned), secs, self.format_size(rate))
+    }
+
+    // Options passed on to the serve processes
+    fn serve_args(&self) -> Vec<&str> {
+        let mut args = Vec::new();
+        if self.mkdirs {
+            args.push("--mkdirs");
+        }
+        if self.times {
+            args.push("--times");
+        }
+        if self.exclude_hidden {
+            args.push("--exclude-hidden");
+        }
+        for pattern in &self.exclude {
+            args.extend(&["--exclude", pattern]);
+        }
+        for pattern in &self.include {
+            args.extend(&["--include", pattern]);
+        }
+        if self.atimes {
+            args.push("--atimes");
+        }
+        if self.no_builtin_excludes {
+            args.push("--no-builtin-excludes");
+        }
+        if let Some(marker) = &self.nosync_marker {
+            args.extend(&["--nosync-marker", marker]);
+        }
+        if let Some(dir) = &self.partial_dir {
+            args.extend(&["--partial-dir", dir]);
+        }
+        if self.skip_failed {
+            args.push("--skip-failed");
+        }
+        if self.continue_on_error {
+            args.push("--continue-on-error");
+        }
+        if self.inplace {
+            args.push("--inplace");
+        }
+        args
+    }
+}
+
+#[derive(Default)]
+struct SyncStats {
+    files: usize,
+    chunks: usize,
+    bytes: u64,
+    logical_bytes: u64
+}
+
+impl SyncStats {
+    // Percentage of the written file data that didn't need transfer (thanks to deduplication)
+    fn dedup_savings(&self) -> f64 {
+        if self.logical_bytes == 0 || self.bytes >= self.logical_bytes {
+            return 0.0;
+        }
+        (self.logical_bytes - self.bytes) as f64 * 100.0 / self.logical_bytes as f64
+    }
+}
+
 struct NodeState {
     id: u8,
+    label: String,
+    prefix: Option<String>,
+    read_only: bool,
+    version: u32,
+    // Clock of the node minus the local clock (in seconds)
+    clock_skew: i64,
     send: RefCell<async_process::ChildStdin>,
     recv: RefCell<async_std::io::BufReader<async_process::ChildStdout>>,
+    child: async_process::Child,
+    stderr: RefCell<Option<task::JoinHandle<()>>>,
     dir: BTreeMap<path::PathBuf, Box<FileData>>,
+    dirs: BTreeMap<path::PathBuf, Box<DirData>>,
     chunks: BTreeSet<String>,
-    missing: RefCell<BTreeSet<String>>
+    missing: RefCell<BTreeSet<String>>,
+    scanned: u64,
+    scan_time: time::Duration
 }
 
 impl PartialEq for NodeState {
@@ -27,10 +202,16 @@ impl PartialEq for NodeState {
     }
 }
 
+impl fmt::Display for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.id, self.label)
+    }
+}
+
 impl NodeState {
     async fn write_file(&self, file: &FileData, trans_data: bool) -> Result<(), Box<dyn Error>> {
         if trans_data {
-            writeln!(self.send.borrow_mut(), "FD:{}:{}:{}:{}:{}:{}", file.path.to_str().expect(""), file.mode, file.user, file.group, file.size, file.mtime).await?;
+            writeln!(self.send.borrow_mut(), "FD:{}:{}:{}:{}:{}:{}:{}", util::encode_path(&file.path), file.mode, file.user, file.group, file.size, file.mtime, file.atime).await?;
             for chunk in &file.chunks {
                 if self.chunks.get(&chunk.hash).is_none() {
                     // Chunk needs transfer
@@ -43,7 +224,118 @@ impl NodeState {
             }
             writeln!(self.send.borrow_mut(), ".").await?;
         } else {
-            writeln!(self.send.borrow_mut(), "FM:{}:{}:{}:{}:{}:{}", file.path.to_str().expect(""), file.mode, file.user, file.group, file.size, file.mtime).await?;
+            writeln!(self.send.borrow_mut(), "FM:{}:{}:{}:{}:{}:{}:{}", util::encode_path(&file.path), file.mode, file.user, file.group, file.size, file.mtime, file.atime).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_dir(&self, dir: &DirData) -> Result<(), Box<dyn Error>> {
+        writeln!(self.send.borrow_mut(), "D:{}:{}:{}:{}:{}", util::encode_path(&dir.path), dir.mode, dir.user, dir.group, dir.mtime).await?;
+        Ok(())
+    }
+
+    // Whether the path is within the part of the tree this node synchronizes (read-only nodes
+    // are only sources)
+    fn covers(&self, path: &path::Path) -> bool {
+        !self.read_only && self.prefix.as_ref().is_none_or(|prefix| path.starts_with(prefix))
+    }
+
+    fn missing_chunks<'a>(&'a self, file: &'a FileData) -> impl Iterator<Item=&'a Box<HashChunk>> {
+        file.chunks.iter().filter(move |chunk| !self.chunks.contains(&chunk.hash))
+    }
+
+    // Wait for the node to acknowledge command, "ERROR" lines before it are only reported
+    async fn expect_ok(&self, command: &'static str) -> Result<(), Box<dyn Error>> {
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if self.recv.borrow_mut().read_line(&mut buf).await? == 0 {
+                return Err(ProtocolError::new(self, command, "Connection closed", "").into());
+            }
+            match buf.trim() {
+                "OK" => return Ok(()),
+                line if line.starts_with("ERROR") => eprintln!("Node {}: {}", self, line),
+                line => return Err(ProtocolError::new(self, command, "Unexpected response", line).into())
+            }
+        }
+    }
+
+    // Wait for the node to finish COMMIT, returns the files it couldn't complete (and skipped)
+    async fn expect_commit(&self) -> Result<Vec<path::PathBuf>, Box<dyn Error>> {
+        let mut failed = Vec::new();
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if self.recv.borrow_mut().read_line(&mut buf).await? == 0 {
+                return Err(ProtocolError::new(self, "COMMIT", "Connection closed", "").into());
+            }
+            match buf.trim() {
+                "OK" => return Ok(failed),
+                "ABORTED" => return Err(format!("Node {}: Commit aborted, {} files couldn't be completed (use --skip-failed to commit the rest)",
+                    self, failed.len()).into()),
+                line if line.starts_with("ERROR") => eprintln!("Node {}: {}", self, line),
+                line => match line.strip_prefix("FAILED:").and_then(util::decode_path) {
+                    Some(path) => {
+                        eprintln!("Node {}: Couldn't complete {}", self, path.display());
+                        failed.push(path);
+                    },
+                    None => return Err(ProtocolError::new(self, "COMMIT", "Unexpected response", line).into())
+                }
+            }
+        }
+    }
+
+    // Query the free space on the node, None if the node doesn't support it
+    async fn query_space(&self) -> Result<Option<u64>, Box<dyn Error>> {
+        self.send("SPACE").await?;
+        let mut buf = String::new();
+        if self.recv.borrow_mut().read_line(&mut buf).await? == 0 {
+            return Err(ProtocolError::new(self, "SPACE", "Connection closed", "").into());
+        }
+        match buf.trim().strip_prefix("SPACE:") {
+            Some(space) => Ok(Some(space.parse()?)),
+            None => Ok(None)
+        }
+    }
+
+    // Have the node re-chunk the given files, returns the paths not matching their chunk lists
+    async fn verify(&self, files: &[&FileData]) -> Result<Vec<path::PathBuf>, Box<dyn Error>> {
+        self.send("VERIFY").await?;
+        for file in files {
+            writeln!(self.send.borrow_mut(), "V:{}", util::encode_path(&file.path)).await?;
+            for chunk in &file.chunks {
+                writeln!(self.send.borrow_mut(), "C:{}:{}:{}", chunk.offset, chunk.size, chunk.hash).await?;
+            }
+        }
+        self.send(".").await?;
+        let mut failed = Vec::new();
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if self.recv.borrow_mut().read_line(&mut buf).await? == 0 {
+                return Err(ProtocolError::new(self, "VERIFY", "Connection closed", "").into());
+            }
+            match buf.trim() {
+                "." => break,
+                line => match line.strip_prefix("BAD:") {
+                    Some(path) => match util::decode_path(path) {
+                        Some(path) => failed.push(path),
+                        None => return Err(ProtocolError::new(self, "VERIFY", "Invalid path", line).into())
+                    },
+                    None => return Err(ProtocolError::new(self, "VERIFY", "Unexpected response", line).into())
+                }
+            }
+        }
+        Ok(failed)
+    }
+
+    // Check that the node is alive, this also keeps idle connections open through firewalls
+    async fn ping(&self) -> Result<(), Box<dyn Error>> {
+        self.send("PING").await?;
+        let mut buf = String::new();
+        self.recv.borrow_mut().read_line(&mut buf).await?;
+        if buf.trim() != "PONG" {
+            return Err(ProtocolError::new(self, "PING", "Unexpected response", &buf).into());
         }
         Ok(())
     }
@@ -53,60 +345,134 @@ impl NodeState {
         Ok(())
     }
 
-    async fn do_collect(&mut self) -> Result<(), Box<dyn Error>> {
+    async fn do_handshake(&mut self) -> Result<(), Box<dyn Error>> {
         let mut buf = String::new();
-        let mut file_data: Option<&mut Box<FileData>> = None;
-
         loop {
             buf.clear();
-            self.recv.get_mut().read_line(&mut buf).await?;
+            if self.recv.get_mut().read_line(&mut buf).await? == 0 {
+                return Err(ProtocolError::new(self, "HANDSHAKE", "Connection closed", "").into());
+            }
             if buf.trim() == "." { break; }
+            if buf.starts_with("E:") {
+                return Err(ProtocolError::new(self, "HANDSHAKE", "Remote error", &buf).into());
+            }
+            if let Some(version) = buf.trim().strip_prefix("VERSION:") {
+                match version.parse::<u32>() {
+                    Ok(version) => self.version = version,
+                    Err(_) => return Err(ProtocolError::new(self, "HANDSHAKE", "Invalid version", &buf).into())
+                }
+            }
+            if let Some(time) = buf.trim().strip_prefix("TIME:") {
+                match time.parse::<i64>() {
+                    Ok(time) => self.clock_skew = time - util::unix_time(),
+                    Err(_) => return Err(ProtocolError::new(self, "HANDSHAKE", "Invalid time", &buf).into())
+                }
+            }
             //eprintln!("[{}]HDR: {}", self.id, buf.trim());
         }
+        Ok(())
+    }
+
+    // Wait (a bit) until the rest of the stderr of the exiting serve process is forwarded
+    async fn flush_stderr(&self) {
+        let stderr = self.stderr.borrow_mut().take();
+        if let Some(stderr) = stderr {
+            async_std::future::timeout(time::Duration::from_secs(1), stderr).await.ok();
+        }
+    }
 
-        self.send.get_mut().write_all(b"LIST\n").await?;
+    // Tell how the serve process ended
+    async fn report_exit(&mut self) {
+        let wait = time::Duration::from_secs(1);
+        match async_std::future::timeout(wait, self.child.status()).await {
+            Ok(Ok(status)) => eprintln!("Node {}: serve process exited: {}", self, status),
+            Ok(Err(e)) => eprintln!("Node {}: Can't get the status of the serve process: {}", self, e),
+            Err(_) => eprintln!("Node {}: serve process is still running", self)
+        }
+    }
+
+    async fn do_collect(&mut self, opts: &SyncOptions) -> Result<(), Box<dyn Error>> {
+        let mut buf = String::new();
+        let start = time::Instant::now();
+        let mut last_report = start;
+        let node = self.to_string();
+        let files_from = opts.files_from.as_deref();
+
+        // A node with a prefix only lists that directory
+        let files_from: Option<Vec<String>> = match &self.prefix {
+            Some(prefix) => Some(match files_from {
+                Some(paths) => paths.iter().filter(|p| path::Path::new(p).starts_with(prefix)).cloned().collect(),
+                None => vec![prefix.clone()]
+            }),
+            None => files_from.map(|paths| paths.to_vec())
+        };
+        match files_from {
+            Some(paths) => {
+                self.send.get_mut().write_all(b"LISTF\n").await?;
+                for path in paths {
+                    writeln!(self.send.get_mut(), "{}", util::encode_path(path::Path::new(&path))).await?;
+                }
+                self.send.get_mut().write_all(b".\n").await?;
+            },
+            None => self.send.get_mut().write_all(b"LIST\n").await?
+        }
+        let mut file_data: Option<&mut Box<FileData>> = None;
         loop {
             buf.clear();
-            self.recv.get_mut().read_line(&mut buf).await?;
+            if self.recv.get_mut().read_line(&mut buf).await? == 0 {
+                return Err(ProtocolError::new(&node, "LIST", "Connection closed", "").into());
+            }
             if buf.trim() == "." { break; }
             //println!("[{}]LINE: {}", self.id, buf.trim());
             let fields: Vec<&str> = buf.trim().split(':').collect();
 
             match fields[0] {
                 "F" => {
-                    let path = path::PathBuf::from(fields[1]);
-                    let fd = Box::new(FileData {
-                        path: path.clone(),
-                        mode: fields[2].parse().expect("Child parse error"),
-                        user: fields[3].parse().expect("Child parse error"),
-                        group: fields[4].parse().expect("Child parse error"),
-                        size: fields[5].parse().expect("Child parse error"),
-                        mtime: fields[6].parse().expect("Child parse error"),
-                        chunks: vec![]
-                    });
-                    //file_data = &fd;
+                    let fd = match FileData::from_fields(&fields) {
+                        Some(fd) => Box::new(fd),
+                        None => return Err(ProtocolError::new(&node, "LIST", "Invalid file entry", &buf).into())
+                    };
+                    let path = fd.path.clone();
                     self.dir.insert(fd.path.clone(), fd);
                     file_data = self.dir.get_mut(&path);
-                    //self.dir.insert(path.clone(), file_data);
                 },
                 "C" => {
-                    let hc = Box::new(HashChunk {
-                        hash: String::from(fields[3]),
-                        offset: fields[1].parse().expect("Child parse error"),
-                        size: fields[2].parse().expect("Child parse error")
-                    });
+                    let hc = match HashChunk::from_fields(&fields) {
+                        Some(hc) => Box::new(hc),
+                        None => return Err(ProtocolError::new(&node, "LIST", "Invalid chunk entry", &buf).into())
+                    };
+                    self.scanned += hc.size as u64;
+                    self.chunks.insert(hc.hash.clone());
                     match &mut file_data {
-                        Some(data) => { &data.chunks.push(hc); },
-                        None => { panic!("FIXME"); }
+                        Some(data) => data.chunks.push(hc),
+                        None => return Err(ProtocolError::new(&node, "LIST", "Chunk without file", &buf).into())
+                    }
+                    // Show the throughput of long scans while they run
+                    if last_report.elapsed().as_secs() >= config::SCAN_REPORT_INTERVAL {
+                        last_report = time::Instant::now();
+                        eprintln!("  - NODE {}: scanning, {}", node, opts.scan_rate(self.scanned, start.elapsed()));
                     }
-                    self.chunks.insert(String::from(fields[3]));
-                    //file_data.chunks.push(hc);
                 },
-                _ => panic!("Child parse error: {}", buf.trim())
-                //_ => return Err("Child parse error").into()
-                //_ => return Result::new(Box::new(Err("Child parse error")))
+                "PC" => {
+                    // Chunk of a partial file from an interrupted sync, only available for reuse
+                    match HashChunk::from_fields(&fields) {
+                        Some(hc) => self.chunks.insert(hc.hash),
+                        None => return Err(ProtocolError::new(&node, "LIST", "Invalid chunk entry", &buf).into())
+                    };
+                },
+                "D" => {
+                    let dd = match DirData::from_fields(&fields) {
+                        Some(dd) => Box::new(dd),
+                        None => return Err(ProtocolError::new(&node, "LIST", "Invalid directory entry", &buf).into())
+                    };
+                    self.dirs.insert(dd.path.clone(), dd);
+                    file_data = None;
+                },
+                _ => return Err(ProtocolError::new(&node, "LIST", "Unexpected response", &buf).into())
             }
         }
+        self.scan_time = start.elapsed();
+        eprintln!("  - NODE {}: {} files, {}", node, self.dir.len(), opts.scan_rate(self.scanned, self.scan_time));
 
         Ok(())
     }
@@ -117,40 +483,319 @@ struct SyncState {
 }
 
 impl SyncState {
-    fn add_node(&mut self, send: async_process::ChildStdin, recv: async_std::io::BufReader<async_process::ChildStdout>) {
+    fn add_node(&mut self, label: String, prefix: Option<String>, read_only: bool, conn: connect::Connect) {
         let node = Box::new(NodeState {
             id: self.nodes.len() as u8 + 1,
-            send: RefCell::new(send),
-            recv: RefCell::new(recv),
+            label,
+            prefix,
+            read_only,
+            version: 0,
+            clock_skew: 0,
+            send: RefCell::new(conn.send),
+            recv: RefCell::new(conn.recv),
+            child: conn.child,
+            stderr: RefCell::new(conn.stderr),
             dir: BTreeMap::new(),
+            dirs: BTreeMap::new(),
             chunks: BTreeSet::new(),
-            missing: RefCell::new(BTreeSet::new())
+            missing: RefCell::new(BTreeSet::new()),
+            scanned: 0,
+            scan_time: time::Duration::default()
         });
         self.nodes.push(node);
     }
 }
 
-pub async fn sync(dirs: Vec<&str>) -> Result<(), Box<dyn Error>> {
+// Directory to connect to and the part of it to sync (None for all of it)
+type Root = (String, Option<String>);
+
+// Split a location without a trailing slash into its parent and the directory to sync
+// in it (relative to the parent), like rsync does. Locations with a slash sync contents.
+fn split_root(location: &str) -> Root {
+    let (host, dir) = connect::split_host(location);
+    if dir.is_empty() || dir.ends_with('/') {
+        return (String::from(location), None);
+    }
+    let (parent, name) = match dir.rfind('/') {
+        Some(0) => ("/", &dir[1..]),
+        Some(pos) => (&dir[..pos], &dir[pos+1..]),
+        None => (".", dir)
+    };
+    if name == "." || name == ".." || name == "~" {
+        return (String::from(location), None);
+    }
+    let root = match host {
+        Some(host) => format!("{}:{}", host, parent),
+        None => String::from(parent)
+    };
+    (root, Some(format!("./{}", name)))
+}
+
+// Like split_root, but the whole path is kept as the prefix (rsync's --relative). A "/./" in the
+// path marks where the kept part starts
+fn relative_root(location: &str) -> Result<Root, Box<dyn Error>> {
+    let (host, dir) = connect::split_host(location);
+    if dir.is_empty() || dir.ends_with('/') {
+        return Ok((String::from(location), None));
+    }
+    let (parent, rel) = match dir.find("/./") {
+        Some(0) => ("/", &dir[3..]),
+        Some(pos) => (&dir[..pos], &dir[pos+3..]),
+        None if dir.starts_with('/') => ("/", &dir[1..]),
+        None if dir.starts_with("~/") => ("~", &dir[2..]),
+        None => (".", dir.trim_start_matches("./"))
+    };
+    let rel = rel.trim_matches('/');
+    if rel.is_empty() || rel == "." || rel == "~" {
+        return Ok((String::from(location), None));
+    }
+    if rel.split('/').any(|name| name == "..") {
+        return Err(format!("Location {} can't be synced with --relative (it contains \"..\")", location).into());
+    }
+    let root = match host {
+        Some(host) => format!("{}:{}", host, parent),
+        None => String::from(parent)
+    };
+    Ok((root, Some(format!("./{}", rel))))
+}
+
+// When a local location is a file, only that file is synced: every location must name the same
+// file, or be the directory (with a trailing slash) to sync it into
+fn file_roots(dirs: &[&str]) -> Result<Option<Vec<Root>>, Box<dyn Error>> {
+    let is_file = |dir: &&&str| match connect::split_host(dir) {
+        (None, local) => path::Path::new(local).is_file(),
+        _ => false
+    };
+    let name = match dirs.iter().find(is_file) {
+        Some(file) => split_root(file).1,
+        None => return Ok(None)
+    };
+    let mut roots = Vec::new();
+    for dir in dirs {
+        if dir.ends_with('/') {
+            roots.push((String::from(*dir), name.clone()));
+        } else {
+            let (root, prefix) = split_root(dir);
+            if prefix != name {
+                return Err(format!("Location {} is not the same file as the other locations", dir).into());
+            }
+            roots.push((root, prefix));
+        }
+    }
+    Ok(Some(roots))
+}
+
+// Find two roots that are the same directory or one inside the other
+fn find_overlap(roots: &[path::PathBuf]) -> Option<(usize, usize)> {
+    for (a, root_a) in roots.iter().enumerate() {
+        for (b, root_b) in roots.iter().enumerate().skip(a + 1) {
+            if root_a.starts_with(root_b) || root_b.starts_with(root_a) {
+                return Some((a, b));
+            }
+        }
+    }
+    None
+}
+
+// Check if any of the (sorted) files is inside the directory
+fn has_files(files: &BTreeSet<&path::Path>, dir: &path::Path) -> bool {
+    // Paths are ordered by components, so the contents of a directory directly follow it
+    files.range::<path::Path, _>((Bound::Excluded(dir), Bound::Unbounded)).next().is_some_and(|file| file.starts_with(dir))
+}
+
+// Reject locations that would sync a directory with itself
+// Two roots whose parts to sync don't contain one another, so nothing could be synced between them
+fn find_disjoint(roots: &[Root]) -> Option<(usize, usize)> {
+    for (a, (_, prefix_a)) in roots.iter().enumerate() {
+        for (b, (_, prefix_b)) in roots.iter().enumerate().skip(a + 1) {
+            if let (Some(prefix_a), Some(prefix_b)) = (prefix_a, prefix_b) {
+                let (prefix_a, prefix_b) = (path::Path::new(prefix_a), path::Path::new(prefix_b));
+                if !prefix_a.starts_with(prefix_b) && !prefix_b.starts_with(prefix_a) {
+                    return Some((a, b));
+                }
+            }
+        }
+    }
+    None
+}
+
+fn check_locations(dirs: &[&str]) -> Result<(), Box<dyn Error>> {
+    let mut locations = Vec::new();
+    let mut roots = Vec::new();
+    for (idx, dir) in dirs.iter().enumerate() {
+        match connect::split_host(dir) {
+            // Missing directories can't overlap (they may be created by --mkdirs)
+            (None, local) => if let Ok(root) = fs::canonicalize(local) {
+                locations.push(dir);
+                roots.push(root);
+            },
+            // Remote paths can only be compared literally
+            (Some(_), _) => if dirs[..idx].contains(dir) {
+                return Err(format!("Location {} is given more than once", dir).into());
+            }
+        }
+    }
+    if let Some((a, b)) = find_overlap(&roots) {
+        let what = if roots[a] == roots[b] { "the same directory" } else { "nested within each other" };
+        return Err(format!("Locations {} and {} are {}", locations[a], locations[b], what).into());
+    }
+    Ok(())
+}
+
+// Resolves with an error message when the sync or its current phase runs out of time. Once commit
+// started it is allowed to finish, so no node is left half committed.
+async fn watchdog(opts: &SyncOptions, phase: &Cell<Phase>) -> String {
+    if opts.timeout == 0 && opts.phase_timeouts.is_empty() {
+        return future::pending().await;
+    }
+    let start = time::Instant::now();
+    let (mut current, mut since) = (phase.get(), start);
+    loop {
+        task::sleep(time::Duration::from_millis(100)).await;
+        if phase.get() != current {
+            current = phase.get();
+            since = time::Instant::now();
+        }
+        if current == Phase::Commit {
+            continue;
+        }
+        if opts.timeout > 0 && start.elapsed().as_secs() >= opts.timeout {
+            return format!("Sync timed out after {} s, nothing was committed", opts.timeout);
+        }
+        if let Some(limit) = opts.phase_timeouts.get(&current) {
+            if since.elapsed().as_secs() >= *limit {
+                return format!("Sync timed out in the {} phase after {} s, nothing was committed", current, limit);
+            }
+        }
+    }
+}
+
+pub async fn sync(dirs: Vec<&str>, opts: &SyncOptions) -> Result<(), Box<dyn Error>> {
+    let phase = Cell::new(Phase::Connect);
+    let sync = Box::pin(sync_nodes(dirs, opts, &phase));
+    let finished = future::select(sync, Box::pin(watchdog(opts, &phase))).await;
+    match finished {
+        future::Either::Left((res, _)) => res,
+        // Dropping the connections makes the nodes discard their temp files, whatever command
+        // they are in the middle of
+        future::Either::Right((msg, _)) => Err(msg.into())
+    }
+}
+
+async fn sync_nodes(dirs: Vec<&str>, opts: &SyncOptions, phase: &Cell<Phase>) -> Result<(), Box<dyn Error>> {
     let mut state = SyncState { nodes: Vec::new() };
 
+    check_locations(&dirs)?;
+    if let Some(id) = opts.read_only.iter().find(|id| **id == 0 || **id > dirs.len()) {
+        return Err(format!("Invalid read-only node number {} (nodes are numbered 1 to {})", id, dirs.len()).into());
+    }
+
     eprintln!("Initializing processes...");
-    for dir in dirs {
-        let conn = connect::connect(dir).await?;
-        state.add_node(conn.send, conn.recv);
+    // Connect in waves of at most max_connections concurrent handshakes
+    let serve_args = opts.serve_args();
+    let roots = match file_roots(&dirs)? {
+        Some(roots) => roots,
+        None if opts.relative => dirs.iter().map(|dir| relative_root(dir)).collect::<Result<_, _>>()?,
+        None => dirs.iter().map(|dir| if opts.trailing_slash { split_root(dir) } else { (String::from(*dir), None) }).collect()
+    };
+    if let Some((a, b)) = find_disjoint(&roots) {
+        return Err(format!("Locations {} and {} are synced as {} and {}, which have nothing in common (add a trailing slash to sync their contents)",
+            dirs[a], dirs[b], roots[a].1.as_deref().unwrap_or_default(), roots[b].1.as_deref().unwrap_or_default()).into());
+    }
+    let wave_size = if opts.max_connections > 0 { opts.max_connections } else { dirs.len() };
+    for (wave, wave_roots) in dirs.chunks(wave_size).zip(roots.chunks(wave_size)) {
+        let first = state.nodes.len();
+        for (dir, (root, prefix)) in wave.iter().zip(wave_roots) {
+            let prefix = prefix.clone();
+            let id = state.nodes.len() + 1;
+            let label = opts.labels.get(&id).cloned().unwrap_or_else(|| String::from(*dir));
+            let tag = format!("{} ({})", id, label);
+            let read_only = opts.read_only.contains(&id);
+            let mut args = serve_args.clone();
+            if read_only {
+                // Serve refuses writes too, in case one slips through
+                args.push("--read-only");
+            }
+            let conn = connect::connect(root, &args, &tag, opts.debug_connect).await?;
+            state.add_node(label, prefix, read_only, conn);
+        }
+        let futs = state.nodes[first..].iter_mut().map(|node| node.do_handshake());
+        let results = future::join_all(futs).await;
+        for (res, node) in results.into_iter().zip(state.nodes[first..].iter_mut()) {
+            if let Err(e) = res {
+                node.flush_stderr().await;
+                if opts.debug_connect {
+                    node.report_exit().await;
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    // Every node must talk a version we do (or the forced one)
+    for node in &state.nodes {
+        if !version_supported(node.version, opts.protocol_version) {
+            quit_nodes(&state).await?;
+            return Err(format!("Node {}: Unsupported protocol version {}", node, node.version).into());
+        }
+    }
+
+    // Mtimes from different clocks can't tell which version is the newest
+    for node in &state.nodes {
+        if node.clock_skew.abs() > config::MAX_CLOCK_SKEW {
+            let msg = format!("Node {}: Its clock differs from the local clock by {} s", node, node.clock_skew);
+            if opts.strict_clock {
+                quit_nodes(&state).await?;
+                return Err(format!("{}, newest-wins decisions would be unreliable", msg).into());
+            }
+            eprintln!("{}", msg);
+        }
     }
 
     eprintln!("Collecting...");
+    phase.set(Phase::Collect);
+    let collecting = Cell::new(state.nodes.len());
     let mut futs: Vec<Pin<Box<dyn future::Future<Output=_>>>> = vec![];
     for node in &mut state.nodes {
-        futs.push(Box::pin(node.do_collect()));
+        let collecting = &collecting;
+        futs.push(Box::pin(async move {
+            let res = node.do_collect(opts).await;
+            collecting.set(collecting.get() - 1);
+            res?;
+            // Nodes done with collection sit idle until the slowest one finishes
+            let mut idle = time::Duration::from_secs(0);
+            while opts.keepalive > 0 && collecting.get() > 0 {
+                task::sleep(time::Duration::from_millis(100)).await;
+                idle += time::Duration::from_millis(100);
+                if idle.as_secs() >= opts.keepalive && collecting.get() > 0 {
+                    node.ping().await?;
+                    idle = time::Duration::from_secs(0);
+                }
+            }
+            Ok::<(), Box<dyn Error>>(())
+        }));
+    }
+    for res in future::join_all(futs).await {
+        res?;
+    }
+
+    if opts.list_only {
+        print_listing(&state, opts);
+        return quit_nodes(&state).await;
+    }
+
+    // A single location is only collected, there is nothing to diff it against
+    if state.nodes.len() < 2 {
+        eprintln!("Only one location given, nothing to sync");
+        return quit_nodes(&state).await;
     }
-    future::join_all(futs).await;
 
     // Do diffing
     eprintln!("Running diff...");
+    phase.set(Phase::Transfer);
     let mut diff: BTreeMap<&path::Path, Option<u8>> = BTreeMap::new();
     for node in &state.nodes {
-        for (path, _) in &node.dir {
+        for (path, _) in node.dir.iter().filter(|(path, _)| opts.in_subpaths(path)) {
             diff.entry(&path).or_insert_with(|| {
                 let mut files: Vec<Option<&Box<FileData>>> = state.nodes.iter().map(|n| n.dir.get(path)).collect();
                 let mut latest: Option<u8> = None;
@@ -174,22 +819,20 @@ pub async fn sync(dirs: Vec<&str>) -> Result<(), Box<dyn Error>> {
     }
     //println!("DIFF: {:?}", diff);
 
-    // Do write meta
-    eprintln!("Sending metadata...");
-    for node in &state.nodes {
-        node.send("WRITE").await?;
-    }
+    // Plan transfers
+    let mut plan: Vec<(usize, &FileData, bool)> = Vec::new();
     for (path, to_do) in diff {
         if let Some(todo) = to_do {
             let files: Vec<Option<&Box<FileData>>> = state.nodes.iter().map(|n| n.dir.get(path)).collect();
             let lfile = &files[todo as usize].unwrap();
 
             for (idx, file) in files.iter().enumerate() {
-                if idx != todo as usize {
+                if idx != todo as usize && state.nodes[idx].covers(path) {
                     let mut trans_meta = false;
                     let mut trans_data = false;
                     if let Some(file) = file {
-                        if file != lfile {
+                        let differs = if opts.no_times { !file.eq_ignore_times(lfile) } else { file != lfile };
+                        if differs {
                             trans_meta = true;
                             if file.chunks != lfile.chunks {
                                 trans_data = true;
@@ -199,21 +842,115 @@ pub async fn sync(dirs: Vec<&str>) -> Result<(), Box<dyn Error>> {
                         trans_meta = true;
                         trans_data = true;
                     }
-                    if trans_meta {
-                        let node = &state.nodes[idx];
-                        node.write_file(&lfile, trans_data).await?;
+                    // Without data only matching contents get their metadata. Placeholder files are not
+                    // created: their new mtime would make them win over the real data in the next sync
+                    if trans_meta && !(opts.no_data && trans_data) {
+                        plan.push((idx, lfile, trans_data));
                     }
                 }
             }
         }
     }
 
+    // Plan directory creation, from the node with the latest version of each directory
+    let files: BTreeSet<&path::Path> = state.nodes.iter().flat_map(|n| n.dir.keys()).map(|p| p.as_path()).collect();
+    let mut mkdirs: BTreeMap<&path::Path, Vec<usize>> = BTreeMap::new();
+    for node in &state.nodes {
+        for path in node.dirs.keys() {
+            if !opts.in_subpaths(path) || opts.prune_empty_dirs && !has_files(&files, path) {
+                continue;
+            }
+            mkdirs.entry(path).or_insert_with(|| {
+                state.nodes.iter().enumerate()
+                    .filter(|(_, n)| n.covers(path) && !n.dirs.contains_key(path) && !n.dir.contains_key(path))
+                    .map(|(idx, _)| idx)
+                    .collect()
+            });
+        }
+    }
+    let mut dir_plan: Vec<(usize, &DirData)> = Vec::new();
+    for (path, nodes) in mkdirs {
+        let latest = state.nodes.iter().filter_map(|n| n.dirs.get(path)).max_by_key(|d| d.mtime).expect("Listed path without directory");
+        dir_plan.extend(nodes.into_iter().map(|idx| (idx, latest.as_ref())));
+    }
+
+    // With times, existing directories get the mtime of the latest version too: when it differs,
+    // or when writing entries into them changes it
+    let mut dir_times: Vec<(usize, &DirData)> = Vec::new();
+    if opts.times || opts.atimes {
+        let touched: BTreeSet<(usize, &path::Path)> = plan.iter()
+            .filter(|(_, _, trans_data)| *trans_data)
+            .map(|(idx, file, _)| (*idx, file.path.as_path()))
+            .chain(dir_plan.iter().map(|(idx, dir)| (*idx, dir.path.as_path())))
+            .filter_map(|(idx, path)| Some((idx, path.parent()?)))
+            .collect();
+        let paths: BTreeSet<&path::Path> = state.nodes.iter().flat_map(|n| n.dirs.keys()).map(|p| p.as_path()).filter(|p| opts.in_subpaths(p)).collect();
+        for path in paths {
+            let latest = state.nodes.iter().filter_map(|n| n.dirs.get(path)).max_by_key(|d| d.mtime).expect("Listed path without directory");
+            for (idx, node) in state.nodes.iter().enumerate() {
+                if let Some(dir) = node.dirs.get(path).filter(|_| node.covers(path)) {
+                    if dir.mtime != latest.mtime || touched.contains(&(idx, path)) {
+                        dir_times.push((idx, latest.as_ref()));
+                    }
+                }
+            }
+        }
+    }
+
+    let node_chunks: Vec<&BTreeSet<String>> = state.nodes.iter().map(|n| &n.chunks).collect();
+    let missing = transfer_chunks(&plan, &node_chunks);
+
+    if opts.dry_run {
+        let estimate = estimate(&plan, &missing);
+        eprintln!("Would create {} directories, update {} files ({} of file data), would transfer {} across {} chunks (deduplication saves {:.1}%)",
+            dir_plan.len(), estimate.files, opts.format_size(estimate.logical_bytes), opts.format_size(estimate.bytes),
+            estimate.chunks, estimate.dedup_savings());
+        quit_nodes(&state).await?;
+        return Ok(());
+    }
+    // The transferred chunks are counted as they go
+    let mut stats = SyncStats { chunks: 0, bytes: 0, ..estimate(&plan, &missing) };
+
+    // Check free space, temp files are written in full before they replace the originals
+    let mut needed: BTreeMap<usize, u64> = BTreeMap::new();
+    for (idx, file, trans_data) in &plan {
+        if *trans_data {
+            *needed.entry(*idx).or_insert(0) += file.size;
+        }
+    }
+    for (idx, needed) in needed {
+        let node = &state.nodes[idx];
+        if let Some(available) = node.query_space().await? {
+            if needed + opts.min_free > available {
+                quit_nodes(&state).await?;
+                return Err(format!("Node {}: Insufficient space: needed {}, available {}",
+                    node, opts.format_size(needed + opts.min_free), opts.format_size(available)).into());
+            }
+        }
+    }
+
+    // Do write meta
+    eprintln!("Sending metadata...");
+    for node in &state.nodes {
+        node.send("WRITE").await?;
+    }
+    for (idx, dir) in dir_plan.iter().chain(&dir_times) {
+        state.nodes[*idx].write_dir(dir).await?;
+    }
+    for (idx, file, trans_data) in &plan {
+        state.nodes[*idx].write_file(file, *trans_data).await?;
+    }
+
     // Do chunk transfers
     eprintln!("Transfering data chunks...");
     let mut done: BTreeSet<String> = BTreeSet::new();
+    let mut vanished: BTreeSet<String> = BTreeSet::new();
     for srcnode in &state.nodes {
-        eprintln!("  - NODE {}", srcnode.id);
-        srcnode.send(".\nREAD").await?;
+        eprintln!("  - NODE {}", srcnode);
+        // End the metadata session first, so its errors aren't mixed into the chunk data
+        srcnode.send(".").await?;
+        srcnode.expect_ok("WRITE").await?;
+        srcnode.send("READ").await?;
         for dstnode in &state.nodes {
             if dstnode != srcnode {
                 let missing = dstnode.missing.borrow_mut();
@@ -235,25 +972,44 @@ pub async fn sync(dirs: Vec<&str>) -> Result<(), Box<dyn Error>> {
         let mut chunkdata = String::new();
         loop {
             buf.clear();
-            recv.read_line(&mut buf).await?;
-            if chunk == "" && &buf[..2] == "C:" {
-                chunk.clear();
-                chunk.push_str(&buf.trim()[2..]);
+            if recv.read_line(&mut buf).await? == 0 {
+                return Err(ProtocolError::new(srcnode, "READ", "Connection closed", "").into());
+            }
+            if let (true, Some(hash)) = (chunk.is_empty(), buf.trim().strip_prefix("C:")) {
+                chunk.push_str(hash);
                 chunkdata.clear();
+            } else if let (true, Some(hash)) = (chunk.is_empty(), buf.trim().strip_prefix("V:")) {
+                // The source changed or vanished since collection, ask the next node holding it
+                vanished.insert(String::from(hash));
+                done.remove(hash);
             } else if &chunk == "" && buf.trim() == "." {
                 break;
             } else if buf.trim() == "." {
                 chunkdata.push('.');
+                stats.chunks += 1;
+                stats.bytes += *missing.get(chunk.as_str()).unwrap_or(&0) as u64;
                 let data = &["C:", &chunk, "\n", &chunkdata].join("");
+                let mut sent_to = Vec::new();
                 for dstnode in &state.nodes {
                     if dstnode != srcnode && dstnode.missing.borrow().get(&chunk).is_some() {
                         // Send chunk
                         dstnode.send(&data).await?;
                         dstnode.missing.borrow_mut().remove(&chunk);
+                        sent_to.push(dstnode.id.to_string());
                     }
                 }
+                vanished.remove(&chunk);
+                if opts.trace_chunks {
+                    // <hash> <size> <source node> <destination nodes>, tab separated
+                    println!("{}\t{}\t{}\t{}", chunk, missing.get(chunk.as_str()).unwrap_or(&0), srcnode.id, sent_to.join(","));
+                }
                 chunk.clear();
                 chunkdata.clear();
+            } else if chunk.is_empty() {
+                return Err(ProtocolError::new(srcnode, "READ", "Unexpected response", &buf).into());
+            } else if chunkdata.len() > 2 * config::MAX_CHUNK_SIZE {
+                // Way more than the base64 encoding of the biggest chunk
+                return Err(ProtocolError::new(srcnode, "READ", "Chunk too large", &chunk).into());
             } else {
                 chunkdata += &buf;
             }
@@ -261,18 +1017,165 @@ pub async fn sync(dirs: Vec<&str>) -> Result<(), Box<dyn Error>> {
         srcnode.send("WRITE").await?;
     }
 
+    // Drop files with chunks no node could provide, instead of failing them at commit
+    let mut skipped: BTreeSet<(usize, path::PathBuf)> = BTreeSet::new();
+    for (idx, file, trans_data) in &plan {
+        let node = &state.nodes[*idx];
+        if *trans_data && node.missing_chunks(file).any(|chunk| vanished.contains(&chunk.hash)) {
+            eprintln!("  - NODE {}: Skipping {}, the source file vanished", node, file.path.display());
+            node.send(&format!("X:{}", util::encode_path(&file.path))).await?;
+            skipped.insert((*idx, file.path.clone()));
+            stats.files -= 1;
+        }
+    }
+
     // Close WRITE sessions
     for node in &state.nodes {
         node.send(".").await?;
     }
+    if opts.delay_updates {
+        // Make sure every node staged its changes before any of them renames
+        for node in &state.nodes {
+            node.expect_ok("WRITE").await?;
+        }
+    }
 
     // Commit modifications (do renames)
     eprintln!("Commiting changes...");
+    phase.set(Phase::Commit);
     for node in &state.nodes {
         node.send("COMMIT").await?;
     }
+    // Every node is heard out, one aborting doesn't undo the commits of the others
+    let mut failed = 0;
+    let mut aborted = 0;
+    for (idx, node) in state.nodes.iter().enumerate() {
+        let res = async {
+            if !opts.delay_updates {
+                node.expect_ok("WRITE").await?;
+            }
+            node.expect_commit().await
+        }.await;
+        match res {
+            Ok(paths) => {
+                failed += paths.len();
+                skipped.extend(paths.into_iter().map(|path| (idx, path)));
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                aborted += 1;
+            }
+        }
+    }
+    if aborted == state.nodes.len() {
+        return Err(String::from("Commit failed on every node").into());
+    } else if aborted > 0 {
+        return Err(format!("Commit failed on {} nodes, the other {} nodes committed their changes",
+            aborted, state.nodes.len() - aborted).into());
+    }
+    if failed > 0 {
+        eprintln!("Skipped {} files that couldn't be completed", failed);
+        stats.files -= failed;
+    }
 
-    // Quit children
+    if opts.verify_after {
+        eprintln!("Verifying...");
+        let mut failed = 0;
+        for (idx, node) in state.nodes.iter().enumerate() {
+            let files: Vec<&FileData> = plan.iter()
+                .filter(|(i, _, trans_data)| *i == idx && *trans_data)
+                .map(|(_, file, _)| *file)
+                .filter(|file| !skipped.contains(&(idx, file.path.clone())))
+                .collect();
+            if files.is_empty() {
+                continue;
+            }
+            for path in node.verify(&files).await? {
+                eprintln!("  - NODE {}: Verification failed: {}", node, path.display());
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            quit_nodes(&state).await?;
+            return Err(format!("Verification failed for {} files", failed).into());
+        }
+    }
+
+    quit_nodes(&state).await?;
+
+    if stats.logical_bytes > 0 {
+        eprintln!("Wrote {} of file data, transferred {} (deduplication saved {:.1}%)",
+            opts.format_size(stats.logical_bytes), opts.format_size(stats.bytes), stats.dedup_savings());
+    }
+
+    if let Some(cmd) = &opts.post_sync {
+        run_post_sync(cmd, &stats).await;
+    }
+    Ok(())
+}
+
+// Unique chunks to transfer with their sizes, given the chunks each node already has
+fn transfer_chunks<'a>(plan: &[(usize, &'a FileData, bool)], node_chunks: &[&BTreeSet<String>]) -> BTreeMap<&'a str, usize> {
+    let mut missing = BTreeMap::new();
+    for (idx, file, trans_data) in plan {
+        if *trans_data {
+            for chunk in file.chunks.iter().filter(|chunk| !node_chunks[*idx].contains(&chunk.hash)) {
+                missing.insert(chunk.hash.as_str(), chunk.size);
+            }
+        }
+    }
+    missing
+}
+
+// What the plan writes, and transfers if every missing chunk is sent once
+fn estimate(plan: &[(usize, &FileData, bool)], missing: &BTreeMap<&str, usize>) -> SyncStats {
+    SyncStats {
+        files: plan.len(),
+        chunks: missing.len(),
+        bytes: missing.values().map(|size| *size as u64).sum(),
+        logical_bytes: plan.iter().filter(|(_, _, trans_data)| *trans_data).map(|(_, file, _)| file.size).sum()
+    }
+}
+
+// Whether the client can talk to a node announcing this version (0 if it announced none)
+fn version_supported(version: u32, forced: Option<u32>) -> bool {
+    match forced {
+        Some(forced) => version == forced,
+        None => config::SUPPORTED_VERSIONS.contains(&version)
+    }
+}
+
+// Run the post-sync hook, its failure is reported but doesn't fail the sync
+async fn run_post_sync(cmd: &str, stats: &SyncStats) {
+    eprintln!("Running post-sync command...");
+    let status = async_process::Command::new("sh").arg("-c").arg(cmd)
+        .env("SYNCR_FILES", stats.files.to_string())
+        .env("SYNCR_CHUNKS", stats.chunks.to_string())
+        .env("SYNCR_BYTES", stats.bytes.to_string())
+        .status().await;
+    match status {
+        Ok(status) if status.success() => {},
+        Ok(status) => eprintln!("Post-sync command failed: {}", status),
+        Err(e) => eprintln!("Post-sync command failed: {}", e)
+    }
+}
+
+// Print the union of the node listings: presence on each node, size and mtime of the latest version
+fn print_listing(state: &SyncState, opts: &SyncOptions) {
+    let mut paths: BTreeSet<&path::Path> = BTreeSet::new();
+    for node in &state.nodes {
+        paths.extend(node.dir.keys().map(|p| p.as_path()));
+    }
+    for path in paths {
+        let files: Vec<Option<&Box<FileData>>> = state.nodes.iter().map(|n| n.dir.get(path)).collect();
+        let presence: String = files.iter().map(|f| if f.is_some() { 'X' } else { '-' }).collect();
+        let latest = files.iter().flatten().max_by_key(|f| f.mtime).expect("Listed path without file");
+        let size = if opts.human_readable { util::format_bytes(latest.size, opts.binary_units) } else { latest.size.to_string() };
+        println!("{} {:>12} {:>10} {}", presence, size, latest.mtime, path.display());
+    }
+}
+
+async fn quit_nodes(state: &SyncState) -> Result<(), Box<dyn Error>> {
     for node in &state.nodes {
         node.send("QUIT").await?;
         let mut buf = String::new();
@@ -282,7 +1185,137 @@ pub async fn sync(dirs: Vec<&str>) -> Result<(), Box<dyn Error>> {
             if n == 0 || buf.trim() == "." { break; }
             //eprintln!("QUIT: {}", buf.trim());
         }
+        node.flush_stderr().await;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_split_root() {
+        let split = |location| {
+            let (root, prefix) = split_root(location);
+            (root, prefix.unwrap_or_default())
+        };
+        assert_eq!(split("./a/src/"), (String::from("./a/src/"), String::new()));
+        assert_eq!(split("./a/src"), (String::from("./a"), String::from("./src")));
+        assert_eq!(split("src"), (String::from("."), String::from("./src")));
+        assert_eq!(split("/src"), (String::from("/"), String::from("./src")));
+        assert_eq!(split("host:dir/src"), (String::from("host:dir"), String::from("./src")));
+        assert_eq!(split("host:src"), (String::from("host:."), String::from("./src")));
+        assert_eq!(split("."), (String::from("."), String::new()));
+        assert_eq!(split("host:"), (String::from("host:"), String::new()));
+    }
+
+    #[test]
+    fn test_relative_root() {
+        let split = |location| {
+            let (root, prefix) = relative_root(location).unwrap();
+            (root, prefix.unwrap_or_default())
+        };
+        assert_eq!(split("/a/b/c"), (String::from("/"), String::from("./a/b/c")));
+        assert_eq!(split("/a/./b/c"), (String::from("/a"), String::from("./b/c")));
+        assert_eq!(split("./a/b"), (String::from("."), String::from("./a/b")));
+        assert_eq!(split("host:~/a/b"), (String::from("host:~"), String::from("./a/b")));
+        assert_eq!(split("host:/a/b/"), (String::from("host:/a/b/"), String::new()));
+        assert_eq!(split("."), (String::from("."), String::new()));
+        assert!(relative_root("/a/../b").is_err());
+    }
+
+    #[test]
+    fn test_file_roots() {
+        // Relies on the tests running in the package root
+        let roots = file_roots(&["src/main.rs", "host:backup/", "./other/main.rs"]).unwrap().unwrap();
+        let main = Some(String::from("./main.rs"));
+        assert_eq!(roots, vec![
+            (String::from("src"), main.clone()),
+            (String::from("host:backup/"), main.clone()),
+            (String::from("./other"), main)
+        ]);
+        assert!(file_roots(&["src/main.rs", "host:backup/other.rs"]).is_err());
+        assert!(file_roots(&["src", "host:backup"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_version_supported() {
+        assert!(version_supported(config::PROTOCOL_VERSION, None));
+        // Version 1 nodes have the old path, file and directory formats
+        assert!(!version_supported(1, None));
+        assert!(!version_supported(0, None));
+        assert!(!version_supported(1, Some(2)));
+        assert!(version_supported(2, Some(2)));
+    }
+
+    #[test]
+    fn test_dedup_savings() {
+        let stats = |bytes, logical_bytes| SyncStats { bytes, logical_bytes, ..Default::default() };
+        assert_eq!(stats(0, 0).dedup_savings(), 0.0);
+        assert_eq!(stats(100, 400).dedup_savings(), 75.0);
+        assert_eq!(stats(400, 400).dedup_savings(), 0.0);
+    }
+
+    #[test]
+    fn test_estimate() {
+        let file = |path: &str, chunks: &[(&str, usize)]| {
+            let mut offset = 0;
+            FileData {
+                path: path::PathBuf::from(path), mode: 0o644, user: 0, group: 0,
+                size: chunks.iter().map(|(_, size)| *size as u64).sum(), mtime: 0, atime: 0,
+                chunks: chunks.iter().map(|(hash, size)| {
+                    offset += *size as u64;
+                    Box::new(HashChunk { hash: String::from(*hash), offset: offset - *size as u64, size: *size })
+                }).collect()
+            }
+        };
+        let (f1, f2, f3) = (file("./f1", &[("a", 100), ("b", 200)]), file("./f2", &[("b", 200), ("c", 50)]), file("./f3", &[("d", 10)]));
+        let has_a: BTreeSet<String> = ["a"].iter().map(|h| String::from(*h)).collect();
+        let has_none = BTreeSet::new();
+        // f1 goes to both nodes, f2 to the second one, f3 only gets its metadata updated
+        let plan = vec![(0, &f1, true), (1, &f1, true), (1, &f2, true), (0, &f3, false)];
+        let missing = transfer_chunks(&plan, &[&has_a, &has_none]);
+        assert_eq!(missing.into_iter().collect::<Vec<_>>(), vec![("a", 100), ("b", 200), ("c", 50)]);
+        let stats = estimate(&plan, &transfer_chunks(&plan, &[&has_a, &has_none]));
+        assert_eq!((stats.files, stats.chunks, stats.bytes, stats.logical_bytes), (4, 3, 350, 850));
+        assert_eq!(stats.dedup_savings(), 500.0 * 100.0 / 850.0);
+    }
+
+    #[test]
+    fn test_phase_names() {
+        for phase in &[Phase::Connect, Phase::Collect, Phase::Transfer] {
+            assert_eq!(phase.to_string().parse::<Phase>(), Ok(*phase));
+        }
+        assert!("commit".parse::<Phase>().is_err());
+        assert!("diff".parse::<Phase>().is_err());
+    }
+
+    #[test]
+    fn test_has_files() {
+        let files: BTreeSet<&path::Path> = ["./a b", "./a/b/c", "./d"].iter().map(path::Path::new).collect();
+        assert!(has_files(&files, path::Path::new("./a")));
+        assert!(has_files(&files, path::Path::new("./a/b")));
+        assert!(!has_files(&files, path::Path::new("./a/c")));
+        assert!(!has_files(&files, path::Path::new("./b")));
+        assert!(!has_files(&files, path::Path::new("./d")));
+    }
+
+    #[test]
+    fn test_find_disjoint() {
+        let roots = |locations: &[&str]| locations.iter().map(|location| split_root(location)).collect::<Vec<_>>();
+        assert_eq!(find_disjoint(&roots(&["./src", "./backup"])), Some((0, 1)));
+        assert_eq!(find_disjoint(&roots(&["./src", "./backup/", "host:src"])), None);
+        assert_eq!(find_disjoint(&roots(&["./a/", "./src", "./b/", "./dest"])), Some((1, 3)));
+        assert_eq!(find_disjoint(&[(String::from("/"), Some(String::from("./a/b"))), (String::from("/x"), Some(String::from("./a")))]), None);
+    }
+
+    #[test]
+    fn test_find_overlap() {
+        let roots = |paths: &[&str]| paths.iter().map(path::PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(find_overlap(&roots(&["/a", "/b", "/c"])), None);
+        assert_eq!(find_overlap(&roots(&["/a", "/b", "/a"])), Some((0, 2)));
+        assert_eq!(find_overlap(&roots(&["/a/b", "/c", "/a"])), Some((0, 2)));
+        assert_eq!(find_overlap(&roots(&["/a", "/ab"])), None);
+    }
+}
@@ -1,4 +1,7 @@
-use std::path;
+use std::{path, str};
+
+use crate::config;
+use crate::util;
 
 #[derive(Debug)]
 pub struct FileChunk {
@@ -14,7 +17,7 @@ pub struct HashChunk {
     pub size: usize
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Debug)]
 pub struct FileData {
     pub path: path::PathBuf,
     pub mode: u32,
@@ -22,5 +25,109 @@ pub struct FileData {
     pub group: u32,
     pub size: u64,
     pub mtime: u32,
+    pub atime: u32,
     pub chunks: Vec<Box<HashChunk>>
 }
+
+#[derive(PartialEq, Debug)]
+pub struct DirData {
+    pub path: path::PathBuf,
+    pub mode: u32,
+    pub user: u32,
+    pub group: u32,
+    pub mtime: u32
+}
+
+// Parse a field of a protocol line
+fn field<T: str::FromStr>(fields: &[&str], idx: usize) -> Option<T> {
+    fields.get(idx)?.parse().ok()
+}
+
+impl HashChunk {
+    // Parse the fields of a chunk line: <type>:<offset>:<size>:<hash>. Chunks are never larger
+    // than MAX_CHUNK_SIZE, bigger sizes are rejected before anything gets allocated for them.
+    pub fn from_fields(fields: &[&str]) -> Option<HashChunk> {
+        let size = field(fields, 2).filter(|size| *size <= config::MAX_CHUNK_SIZE)?;
+        Some(HashChunk {
+            hash: String::from(*fields.get(3)?),
+            offset: field(fields, 1)?,
+            size
+        })
+    }
+}
+
+impl FileData {
+    // Parse the fields of a file line: <type>:<path>:<mode>:<user>:<group>:<size>:<mtime>[:<atime>]
+    pub fn from_fields(fields: &[&str]) -> Option<FileData> {
+        Some(FileData {
+            path: util::decode_path(fields.get(1)?)?,
+            mode: field(fields, 2)?,
+            user: field(fields, 3)?,
+            group: field(fields, 4)?,
+            size: field(fields, 5)?,
+            mtime: field(fields, 6)?,
+            atime: if fields.len() > 7 { field(fields, 7)? } else { 0 },
+            chunks: vec![]
+        })
+    }
+}
+
+impl DirData {
+    // Parse the fields of a directory line: <type>:<path>:<mode>:<user>:<group>:<mtime>
+    pub fn from_fields(fields: &[&str]) -> Option<DirData> {
+        Some(DirData {
+            path: util::decode_path(fields.get(1)?)?,
+            mode: field(fields, 2)?,
+            user: field(fields, 3)?,
+            group: field(fields, 4)?,
+            mtime: field(fields, 5)?
+        })
+    }
+}
+
+impl FileData {
+    // Compare everything but the timestamps
+    pub fn eq_ignore_times(&self, other: &Self) -> bool {
+        self.path == other.path && self.mode == other.mode && self.user == other.user && self.group == other.group
+            && self.size == other.size && self.chunks == other.chunks
+    }
+}
+
+// atime is not compared: it changes whenever the file is read, so it never triggers a transfer
+impl PartialEq for FileData {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_ignore_times(other) && self.mtime == other.mtime
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_file_data_eq_ignores_atime() {
+        let file = |mtime, atime| FileData {
+            path: path::PathBuf::from("./a"), mode: 0o100644, user: 0, group: 0, size: 0, mtime, atime, chunks: vec![]
+        };
+        assert_eq!(file(10, 20), file(10, 30));
+        assert_ne!(file(10, 20), file(11, 20));
+        assert!(file(10, 20).eq_ignore_times(&file(11, 30)));
+    }
+
+    #[test]
+    fn test_from_fields() {
+        let fd = FileData::from_fields(&["F", "./a", "33188", "1000", "100", "12", "1600000000"]).unwrap();
+        assert_eq!(fd.path, path::PathBuf::from("./a"));
+        assert_eq!((fd.mode, fd.size, fd.mtime, fd.atime), (33188, 12, 1600000000, 0));
+        assert!(FileData::from_fields(&["F", "./a", "x", "1000", "100", "12", "1600000000"]).is_none());
+        assert!(FileData::from_fields(&["F", "./a"]).is_none());
+        let hc = HashChunk::from_fields(&["C", "0", "12", "abc"]).unwrap();
+        assert_eq!(hc, HashChunk { hash: String::from("abc"), offset: 0, size: 12 });
+        assert!(HashChunk::from_fields(&["C", "0", "12"]).is_none());
+        assert!(HashChunk::from_fields(&["C", "0", &config::MAX_CHUNK_SIZE.to_string(), "abc"]).is_some());
+        assert!(HashChunk::from_fields(&["C", "0", &(config::MAX_CHUNK_SIZE + 1).to_string(), "abc"]).is_none());
+        assert!(HashChunk::from_fields(&["C", "0", "18446744073709551615", "abc"]).is_none());
+        let dd = DirData::from_fields(&["D", "./d", "16877", "1000", "100", "1600000000"]).unwrap();
+        assert_eq!((dd.mode, dd.user, dd.group, dd.mtime), (16877, 1000, 100, 1600000000));
+        assert!(DirData::from_fields(&["D", "./d", "1000", "100"]).is_none());
+    }
+}
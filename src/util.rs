@@ -1,6 +1,9 @@
 use sha::utils::{Digest, DigestExt};
 use std::hash::Hasher;
 use sha::sha1::Sha1 as Sha;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::{path, str, time};
 
 pub fn hash(buf: &[u8]) -> String {
     let mut hasher = Sha::default();
@@ -9,6 +12,72 @@ pub fn hash(buf: &[u8]) -> String {
     return hasher.to_hex();
 }
 
+// Format a byte count with an adaptive unit (KB, MB... or KiB, MiB... when binary)
+pub fn format_bytes(n: u64, binary: bool) -> String {
+    let (base, units) = if binary {
+        (1024.0, ["B", "KiB", "MiB", "GiB", "TiB", "PiB"])
+    } else {
+        (1000.0, ["B", "KB", "MB", "GB", "TB", "PB"])
+    };
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", n, units[0])
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+// Current time in seconds since the epoch, like the mtimes in the listings
+pub fn unix_time() -> i64 {
+    time::SystemTime::now().duration_since(time::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+// Quote an argument for the remote shell (ssh passes the command line through it)
+pub fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c)) {
+        String::from(arg)
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// Encode a path for the protocol: ':' (field separator), '%', spaces, control characters
+// and non-ASCII bytes are percent-encoded, so any file name survives a protocol line
+pub fn encode_path(path: &path::Path) -> String {
+    let mut encoded = String::new();
+    for &b in path.as_os_str().as_bytes() {
+        if b <= b' ' || b >= 0x7f || b == b':' || b == b'%' {
+            encoded.push_str(&format!("%{:02X}", b));
+        } else {
+            encoded.push(b as char);
+        }
+    }
+    encoded
+}
+
+// Decode a path encoded by encode_path, None if it has an invalid escape
+pub fn decode_path(encoded: &str) -> Option<path::PathBuf> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = str::from_utf8(bytes.get(i+1..i+3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(path::PathBuf::from(OsStr::from_bytes(&decoded)))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -19,4 +88,35 @@ mod test {
         // echo -n 12 | sha1sum
         assert_eq!(res, "7b52009b64fd0a2a49e6d8a939753077792b0554");
     }
+
+    #[test]
+    fn test_encode_path() {
+        let name = path::Path::new(OsStr::from_bytes(b"./a b/c:d%\xff\n.txt"));
+        assert_eq!(encode_path(name), "./a%20b/c%3Ad%25%FF%0A.txt");
+        assert_eq!(decode_path(&encode_path(name)).unwrap(), name);
+        assert_eq!(decode_path("./plain").unwrap(), path::Path::new("./plain"));
+        assert!(decode_path("./bad%2").is_none());
+        assert!(decode_path("./bad%zz").is_none());
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("--exclude"), "--exclude");
+        assert_eq!(shell_quote("*.log"), "'*.log'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0, false), "0 B");
+        assert_eq!(format_bytes(999, false), "999 B");
+        assert_eq!(format_bytes(1000, false), "1.0 KB");
+        assert_eq!(format_bytes(1500000, false), "1.5 MB");
+        assert_eq!(format_bytes(3_000_000_000_000, false), "3.0 TB");
+        assert_eq!(format_bytes(1023, true), "1023 B");
+        assert_eq!(format_bytes(1024, true), "1.0 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024, true), "5.0 GiB");
+        assert_eq!(format_bytes(u64::MAX, false), "18446.7 PB");
+    }
 }